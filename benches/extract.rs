@@ -0,0 +1,34 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::io;
+
+const WORDS: &[&str] = &["alpha", "beta", "Les Misérables", "Émile Zola", "1980", "67.89"];
+
+// --------------------------------------------------
+fn tsv(rows: usize) -> Vec<u8> {
+    let mut data = String::new();
+    for row in 0..rows {
+        let fields = (0..10)
+            .map(|col| WORDS[(row * 7 + col * 3) % WORDS.len()])
+            .collect::<Vec<_>>();
+        data.push_str(&fields.join("\t"));
+        data.push('\n');
+    }
+    data.into_bytes()
+}
+
+// --------------------------------------------------
+fn extract(c: &mut Criterion) {
+    let data = tsv(50_000);
+    let mut group = c.benchmark_group("extract");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    for args in [["-f", "1,3,5-7"], ["-b", "1-20"], ["-c", "1-20"]] {
+        let config = cutr::get_args_from(["cutr", args[0], args[1]]).unwrap();
+        group.bench_function(args.join(" "), |b| {
+            b.iter(|| cutr::cut(&config, data.as_slice(), &mut config.writer(io::sink())).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, extract);
+criterion_main!(benches);
@@ -0,0 +1,85 @@
+use crate::MyResult;
+use std::fmt::Write;
+
+/// A column added after the selected fields, given as `NAME=EXPR`.
+///
+/// `EXPR` is one of the variables `$FILENAME`, the name of the input file,
+/// `$LINE`, the number of the line the record starts on, and `$NF`, the
+/// number of fields in the record, or else a literal value. A literal
+/// starting with `$` is written with `$$`.
+#[derive(Debug)]
+pub struct Column {
+    pub name: String,
+    value: Value,
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Literal(String),
+    Filename,
+    Line,
+    FieldCount,
+}
+
+impl Column {
+    pub fn parse(spec: &str) -> MyResult<Self> {
+        let (name, expr) = spec.split_once('=')
+            .filter(|(name, _)| !name.is_empty())
+            .ok_or_else(|| format!("illegal --add-column \"{}\": expected NAME=EXPR", spec))?;
+        let value = match expr {
+            "$FILENAME" => Value::Filename,
+            "$LINE" => Value::Line,
+            "$NF" => Value::FieldCount,
+            _ => match expr.strip_prefix('$') {
+                Some(literal) if literal.starts_with('$') => Value::Literal(literal.to_string()),
+                Some(_) => {
+                    return Err(From::from(format!(
+                        "illegal --add-column \"{}\": unknown variable {}", spec, expr
+                    )));
+                }
+                None => Value::Literal(expr.to_string()),
+            },
+        };
+        Ok(Column { name: name.to_string(), value })
+    }
+
+    /// Appends the value of the column for a record of `filename` with
+    /// `fields` fields, starting on line `line`, to `buf`.
+    pub fn write_value(&self, buf: &mut String, filename: &str, line: usize, fields: usize) {
+        match &self.value {
+            Value::Literal(literal) => buf.push_str(literal),
+            Value::Filename => buf.push_str(filename),
+            Value::Line => write!(buf, "{}", line).unwrap(),
+            Value::FieldCount => write!(buf, "{}", fields).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::{Column, Value};
+
+    #[test]
+    fn test_parse() {
+        assert!(Column::parse("source").is_err());
+        assert!(Column::parse("n=$NR").is_err());
+        let column = Column::parse("source=fileA=1").unwrap();
+        assert_eq!(column.name, "source");
+        assert_eq!(column.value, Value::Literal("fileA=1".to_string()));
+        assert_eq!(Column::parse("f=$FILENAME").unwrap().value, Value::Filename);
+        assert_eq!(
+            Column::parse("=$LINE").unwrap_err().to_string(),
+            "illegal --add-column \"=$LINE\": expected NAME=EXPR"
+        );
+        assert_eq!(Column::parse("l=$LINE").unwrap().value, Value::Line);
+        assert_eq!(Column::parse("price=$$5").unwrap().value, Value::Literal("$5".to_string()));
+        assert_eq!(Column::parse("empty=").unwrap().value, Value::Literal(String::new()));
+
+        let mut buf = String::new();
+        for spec in ["a=x", "b=$FILENAME", "c=$LINE", "d=$NF"] {
+            Column::parse(spec).unwrap().write_value(&mut buf, "in.csv", 7, 3);
+            buf.push(',');
+        }
+        assert_eq!(buf, "x,in.csv,7,3,");
+    }
+}
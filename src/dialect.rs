@@ -0,0 +1,296 @@
+use crate::{trim_eol, MyResult};
+use csv::{ByteRecord, Position, Reader, ReaderBuilder};
+use memchr::{memchr, memchr2};
+use std::io::BufRead;
+
+/// How the fields of a delimited file are separated, quoted and escaped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dialect {
+    /// Field delimiter. Lines are split without quoting, as with
+    /// `raw_escape`, when it is not ASCII.
+    pub delimiter: char,
+    /// Bytes that all delimit fields, splitting lines as with `raw_escape`
+    /// in place of `delimiter` when not empty.
+    pub delimiter_set: Vec<u8>,
+    pub quote: u8,
+    /// Character escaping a quote inside a quoted field, if any.
+    pub escape: Option<u8>,
+    /// Whether two quotes in a quoted field stand for one.
+    pub double_quote: bool,
+    /// Character starting a comment line, if any.
+    pub comment: Option<u8>,
+    /// Whether leading and trailing whitespace is trimmed from fields.
+    pub trim: bool,
+    /// Character escaping the delimiter in lines split without any quoting,
+    /// as in the text format of PostgreSQL `COPY`. Quotes are then ordinary
+    /// characters.
+    pub raw_escape: Option<u8>,
+    /// Whether escape sequences are replaced by the characters they stand
+    /// for when splitting raw lines, as in the text format of PostgreSQL
+    /// `COPY`: `\b`, `\f`, `\n`, `\r`, `\t`, `\v`, octal `\NNN` and `\xHH`,
+    /// any other character standing for itself. A field that is just `\N`,
+    /// a NULL, becomes empty.
+    pub unescape: bool,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect {
+            delimiter: '\t',
+            delimiter_set: vec![],
+            quote: b'"',
+            escape: None,
+            double_quote: true,
+            comment: None,
+            trim: false,
+            raw_escape: None,
+            unescape: false,
+        }
+    }
+}
+
+impl Dialect {
+    /// Returns a builder for flexible CSV readers of this dialect that do
+    /// not treat the first record as a header. The builder does not trim
+    /// fields, ignores `raw_escape` and requires an ASCII delimiter; see
+    /// [`Dialect::records`].
+    pub fn reader(&self) -> ReaderBuilder {
+        let mut builder = ReaderBuilder::new();
+        builder.has_headers(false)
+            .flexible(true)
+            .delimiter(self.delimiter as u8)
+            .quote(self.quote)
+            .escape(self.escape)
+            .double_quote(self.double_quote)
+            .comment(self.comment);
+        builder
+    }
+
+    /// Whether lines are split on the delimiter without any quoting.
+    pub fn is_raw(&self) -> bool {
+        self.raw_escape.is_some() || !self.delimiter.is_ascii() || !self.delimiter_set.is_empty()
+    }
+
+    /// Returns a reader of the records of `input` in this dialect.
+    pub fn records<R: BufRead>(&self, input: R) -> Records<R> {
+        let source = if self.is_raw() {
+            Source::Raw { input, line: vec![], number: 0 }
+        } else {
+            Source::Csv(self.reader().from_reader(input))
+        };
+        Records { dialect: self.clone(), source }
+    }
+
+    /// Returns the character escaping a quote written inside a quoted field,
+    /// or `None` if quotes are doubled instead.
+    pub fn output_escape(&self) -> Option<u8> {
+        if self.double_quote {
+            None
+        } else {
+            Some(self.escape.unwrap_or(b'\\'))
+        }
+    }
+}
+
+/// Reads the records of an input in a [`Dialect`].
+pub struct Records<R> {
+    dialect: Dialect,
+    source: Source<R>,
+}
+
+enum Source<R> {
+    Csv(Reader<R>),
+    Raw {
+        input: R,
+        line: Vec<u8>,
+        number: u64,
+    },
+}
+
+impl<R: BufRead> Records<R> {
+    /// Reads the next record into `record`, as `Reader::read_byte_record`
+    /// does, and returns whether there was one.
+    pub fn read(&mut self, record: &mut ByteRecord) -> MyResult<bool> {
+        let dialect = &self.dialect;
+        match &mut self.source {
+            Source::Csv(reader) => {
+                if !reader.read_byte_record(record)? {
+                    return Ok(false);
+                }
+            }
+            Source::Raw { input, line, number } => loop {
+                line.clear();
+                if input.read_until(b'\n', line)? == 0 {
+                    return Ok(false);
+                }
+                *number += 1;
+                let text = trim_eol(line.strip_suffix(b"\n").unwrap_or(line));
+                if dialect.comment.is_some() && text.first() == dialect.comment.as_ref() {
+                    continue;
+                }
+                split_escaped(text, dialect, record);
+                let mut pos = Position::new();
+                pos.set_line(*number);
+                record.set_position(Some(pos));
+                break;
+            },
+        }
+        // Trimming is done here since the csv crate does not trim the first
+        // record of a reader without headers.
+        if dialect.trim {
+            record.trim();
+        }
+        Ok(true)
+    }
+}
+
+/// Splits `line` into `record` on the delimiters of `dialect` that are not
+/// preceded by its `raw_escape`, unescaping the fields if asked to.
+fn split_escaped(line: &[u8], dialect: &Dialect, record: &mut ByteRecord) {
+    // The raw text of a NULL field, decoded as an empty field.
+    let null = [dialect.raw_escape.unwrap_or_default(), b'N'];
+    let null = (dialect.unescape && dialect.raw_escape.is_some()).then_some(&null[..]);
+    let mut buf = [0; 4];
+    let delimiter = dialect.delimiter.encode_utf8(&mut buf).as_bytes();
+    let set = dialect.delimiter_set.as_slice();
+    let escape = dialect.raw_escape;
+    record.clear();
+    let mut field = vec![];
+    let mut field_start = 0;
+    let mut start = 0;
+    let mut rest = 0;
+    let next = |from: usize| match escape {
+        _ if !set.is_empty() => line[from..].iter().position(|b| set.contains(b) || Some(*b) == escape),
+        Some(escape) => memchr2(delimiter[0], escape, &line[from..]),
+        None => memchr(delimiter[0], &line[from..]),
+    };
+    // Length of the delimiter starting at `i`, if there is one.
+    let delimiter_at = |i: usize| match set {
+        [] => line[i..].starts_with(delimiter).then_some(delimiter.len()),
+        _ => set.contains(&line[i]).then_some(1),
+    };
+    while let Some(i) = next(rest).map(|i| rest + i) {
+        if Some(line[i]) == escape && i + 1 < line.len() {
+            if dialect.unescape {
+                field.extend_from_slice(&line[start..i]);
+                let (byte, len) = unescape(&line[i + 1..]);
+                field.push(byte);
+                start = i + 1 + len;
+                rest = start;
+            } else {
+                rest = i + 2;
+            }
+        } else if let Some(len) = delimiter_at(i) {
+            field.extend_from_slice(&line[start..i]);
+            if Some(&line[field_start..i]) == null {
+                field.clear();
+            }
+            record.push_field(&field);
+            field.clear();
+            start = i + len;
+            field_start = start;
+            rest = start;
+        } else {
+            rest = i + 1;
+        }
+    }
+    field.extend_from_slice(&line[start..]);
+    if Some(&line[field_start..]) == null {
+        field.clear();
+    }
+    record.push_field(&field);
+}
+
+/// Decodes the escape sequence starting `seq`, just after the escape
+/// character, into the byte it stands for and the length of the sequence.
+fn unescape(seq: &[u8]) -> (u8, usize) {
+    let digits = |radix: u32, first: usize, max: usize| {
+        let len = seq[first..].iter()
+            .take(max)
+            .take_while(|&&b| char::from(b).is_digit(radix))
+            .count();
+        let value = seq[first..first + len].iter()
+            .fold(0u32, |n, &b| n * radix + char::from(b).to_digit(radix).unwrap());
+        (value as u8, first + len)
+    };
+    match seq[0] {
+        b'b' => (0x08, 1),
+        b'f' => (0x0c, 1),
+        b'n' => (b'\n', 1),
+        b'r' => (b'\r', 1),
+        b't' => (b'\t', 1),
+        b'v' => (0x0b, 1),
+        b'0'..=b'7' => digits(8, 0, 3),
+        b'x' if seq.get(1).is_some_and(u8::is_ascii_hexdigit) => digits(16, 1, 2),
+        c => (c, 1),
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::Dialect;
+    use csv::ByteRecord;
+
+    fn read(dialect: &Dialect, input: &str) -> Vec<Vec<String>> {
+        let mut reader = dialect.records(input.as_bytes());
+        let mut record = ByteRecord::new();
+        let mut records = vec![];
+        while reader.read(&mut record).unwrap() {
+            records.push(record.iter().map(|f| String::from_utf8_lossy(f).into_owned()).collect());
+        }
+        records
+    }
+
+    #[test]
+    fn test_reader() {
+        let csv = Dialect { delimiter: ',', ..Dialect::default() };
+        assert_eq!(read(&csv, "a,\"b,\"\"c\"\"\"\n"), [["a", "b,\"c\""]]);
+
+        let vendor = Dialect {
+            quote: b'\'',
+            escape: Some(b'\\'),
+            double_quote: false,
+            comment: Some(b'#'),
+            trim: true,
+            ..csv.clone()
+        };
+        assert_eq!(read(&vendor, "# comment\n a ,'b,\\'c' \n x,y\n"), [["a", "b,'c"], ["x", "y"]]);
+        assert_eq!(vendor.output_escape(), Some(b'\\'));
+        assert_eq!(csv.output_escape(), None);
+    }
+
+    #[test]
+    fn test_raw_escape() {
+        let copy = Dialect { raw_escape: Some(b'\\'), ..Dialect::default() };
+        let input = "a\\\tb\t\"c\\\\\tx\\ty\\\r\n\n# d\te\\";
+        assert_eq!(
+            read(&copy, input),
+            [vec!["a\\\tb", "\"c\\\\", "x\\ty\\"], vec![""], vec!["# d", "e\\"]]
+        );
+        let copy = Dialect { unescape: true, comment: Some(b'#'), ..copy };
+        assert_eq!(read(&copy, input), [vec!["a\tb", "\"c\\", "x\ty\\"], vec![""]]);
+
+        let decode = |field: &str| read(&copy, field).remove(0);
+        assert_eq!(decode("\\b\\f\\v"), ["\x08\x0c\x0b"]);
+        assert_eq!(decode("x\\011y\\7\\0\\1234"), ["x\ty\x07\0S4"]);
+        assert_eq!(decode("\\x41\\x9\\xg"), ["A\txg"]);
+        assert_eq!(decode("1\t\\N\tx\\N\t\\N"), ["1", "", "xN", ""]);
+        assert_eq!(decode("\\N\\N"), ["NN"]);
+    }
+
+    #[test]
+    fn test_unicode_delimiter() {
+        let arrows = Dialect { delimiter: '→', ..Dialect::default() };
+        assert_eq!(read(&arrows, "a→\"b→c\"\n→x\u{2190}→\n"), [vec!["a", "\"b", "c\""], vec!["", "x\u{2190}", ""]]);
+        let escaped = Dialect { raw_escape: Some(b'\\'), ..arrows };
+        assert_eq!(read(&escaped, "a\\→b→c"), [["a\\→b", "c"]]);
+    }
+
+    #[test]
+    fn test_delimiter_set() {
+        let mixed = Dialect { delimiter_set: b",;|".to_vec(), ..Dialect::default() };
+        assert_eq!(read(&mixed, "a,b;\"c|d\"\n"), [["a", "b", "\"c", "d\""]]);
+        let escaped = Dialect { raw_escape: Some(b'\\'), ..mixed };
+        assert_eq!(read(&escaped, "a\\;b|c\tx"), [["a\\;b", "c\tx"]]);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{select::find_name, MyResult, PositionList};
+use csv::ByteRecord;
+use regex::bytes::Regex;
+use std::str;
+
+/// Selects the data records of each file by their 1-based number, counted
+/// after the header row if there is one.
+#[derive(Debug, Default)]
+pub struct RowSelection {
+    /// Number of leading records to drop.
+    pub skip: usize,
+    /// Records to keep, in the syntax of the position lists.
+    pub rows: Option<PositionList>,
+    /// Maximum number of records to write.
+    pub limit: Option<usize>,
+}
+
+impl RowSelection {
+    /// Whether every record is selected.
+    pub fn is_all(&self) -> bool {
+        self.skip == 0 && self.rows.is_none() && self.limit.is_none()
+    }
+
+    /// Whether record `n` is selected, `written` records having been written.
+    pub fn selects(&self, n: usize, written: usize) -> bool {
+        n > self.skip
+            && self.limit.is_none_or(|limit| written < limit)
+            && self.rows.as_ref().is_none_or(|rows| rows.iter().any(|r| r.contains(&(n - 1))))
+    }
+
+    /// Whether no record after record `n` can be selected.
+    pub fn is_done(&self, n: usize, written: usize) -> bool {
+        self.limit.is_some_and(|limit| written >= limit)
+            || self.rows.as_ref().is_some_and(|rows| rows.iter().all(|r| r.end <= n))
+    }
+}
+
+/// A condition a record must meet to be cut, given as `FIELD OP VALUE`.
+///
+/// `FIELD` is a 1-based field number or the name of a field in the first
+/// record of each file, matched as in the field list. `OP` is one of:
+///
+/// - `=` and `!=`, comparing the field to `VALUE` as text,
+/// - `<`, `<=`, `>` and `>=`, comparing it as a number,
+/// - `~` and `!~`, matching it against the regular expression `VALUE`.
+#[derive(Debug)]
+pub struct Predicate {
+    field: FieldRef,
+    op: Op,
+}
+
+#[derive(Debug, PartialEq)]
+enum FieldRef {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Debug)]
+enum Op {
+    Eq(Vec<u8>),
+    Ne(Vec<u8>),
+    Lt(f64),
+    Le(f64),
+    Gt(f64),
+    Ge(f64),
+    Match(Regex),
+    NotMatch(Regex),
+}
+
+const OPERATORS: &[&str] = &["!=", "!~", "<=", ">=", "=", "<", ">", "~"];
+
+impl Predicate {
+    pub fn parse(expr: &str) -> MyResult<Self> {
+        let illegal = || format!("illegal --where expression: \"{}\"", expr);
+        let start = expr.find(['=', '!', '<', '>', '~']).ok_or_else(illegal)?;
+        let (field, rest) = expr.split_at(start);
+        let op = OPERATORS.iter().find(|op| rest.starts_with(*op)).ok_or_else(illegal)?;
+        let value = &rest[op.len()..];
+        let field = match field.trim() {
+            "" => return Err(From::from(illegal())),
+            f if f.bytes().all(|b| b.is_ascii_digit()) => match f.parse::<usize>() {
+                Ok(n) if n > 0 => FieldRef::Index(n - 1),
+                _ => return Err(From::from(illegal())),
+            },
+            f => FieldRef::Name(f.to_string()),
+        };
+        let number = || {
+            value.trim().parse::<f64>()
+                .map_err(|_| format!("--where value \"{}\" is not a number", value))
+        };
+        let op = match *op {
+            "=" => Op::Eq(value.as_bytes().to_vec()),
+            "!=" => Op::Ne(value.as_bytes().to_vec()),
+            "<" => Op::Lt(number()?),
+            "<=" => Op::Le(number()?),
+            ">" => Op::Gt(number()?),
+            ">=" => Op::Ge(number()?),
+            "~" => Op::Match(Regex::new(value)?),
+            _ => Op::NotMatch(Regex::new(value)?),
+        };
+        Ok(Predicate { field, op })
+    }
+
+    /// Whether the field is referred to by name.
+    pub fn is_named(&self) -> bool {
+        matches!(self.field, FieldRef::Name(_))
+    }
+
+    /// Returns the 0-based index of the field in records whose first record
+    /// is `header`.
+    pub fn resolve(&self, header: &ByteRecord) -> MyResult<usize> {
+        match &self.field {
+            FieldRef::Index(i) => Ok(*i),
+            FieldRef::Name(name) => find_name("--where", name, header)?
+                .ok_or_else(|| From::from(format!("--where: no field named \"{}\"", name))),
+        }
+    }
+
+    /// Whether `record` meets the condition, the field being at `index`. A
+    /// missing field is taken to be empty, and a field that is not a number
+    /// fails every numeric comparison.
+    pub fn matches(&self, record: &ByteRecord, index: usize) -> bool {
+        let field = record.get(index).unwrap_or_default();
+        let number = || str::from_utf8(field).ok().and_then(|f| f.trim().parse::<f64>().ok());
+        match &self.op {
+            Op::Eq(value) => field == value.as_slice(),
+            Op::Ne(value) => field != value.as_slice(),
+            Op::Lt(value) => number().is_some_and(|n| n < *value),
+            Op::Le(value) => number().is_some_and(|n| n <= *value),
+            Op::Gt(value) => number().is_some_and(|n| n > *value),
+            Op::Ge(value) => number().is_some_and(|n| n >= *value),
+            Op::Match(re) => re.is_match(field),
+            Op::NotMatch(re) => !re.is_match(field),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::{FieldRef, Predicate, RowSelection};
+    use csv::ByteRecord;
+
+    #[test]
+    fn test_parse() {
+        assert!(Predicate::parse("").is_err());
+        assert!(Predicate::parse("year").is_err());
+        assert!(Predicate::parse("=1980").is_err());
+        assert!(Predicate::parse("0=1980").is_err());
+        assert!(Predicate::parse("year!1980").is_err());
+        assert!(Predicate::parse("year<abc").is_err());
+        assert!(Predicate::parse("title~(").is_err());
+        assert_eq!(Predicate::parse("2>=1980").unwrap().field, FieldRef::Index(1));
+        assert_eq!(
+            Predicate::parse("year=1980").unwrap().field,
+            FieldRef::Name("year".to_string())
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let header = ByteRecord::from(vec!["title", "year"]);
+        let record = ByteRecord::from(vec!["The Blues Brothers", "1980"]);
+        let matches = |expr: &str| {
+            let pred = Predicate::parse(expr).unwrap();
+            pred.matches(&record, pred.resolve(&header).unwrap())
+        };
+        assert!(matches("title=The Blues Brothers"));
+        assert!(!matches("title!=The Blues Brothers"));
+        assert!(matches("1~^The"));
+        assert!(!matches("1!~Blues"));
+        assert!(matches("year<1981"));
+        assert!(matches("year<=1980"));
+        assert!(!matches("year>1980"));
+        assert!(matches("2>=1980.0"));
+        assert!(!matches("title>0"));
+        assert!(matches("3="));
+        assert!(Predicate::parse("genre=x").unwrap().resolve(&header).is_err());
+        assert!(matches(" Year >1979"));
+        let header = ByteRecord::from(vec!["year", "Year"]);
+        assert_eq!(Predicate::parse("Year=1").unwrap().resolve(&header).unwrap(), 1);
+        assert_eq!(
+            Predicate::parse("YEAR=1").unwrap().resolve(&header).unwrap_err().to_string(),
+            "--where: \"YEAR\" matches several fields: 1, 2"
+        );
+    }
+
+    #[test]
+    fn test_row_selection() {
+        let all = RowSelection::default();
+        assert!(all.is_all());
+        assert!(all.selects(1, 0));
+        assert!(!all.is_done(1_000, 1_000));
+
+        let sel = RowSelection { skip: 2, rows: Some(vec![0..4, 9..10]), limit: None };
+        let selected = (1..=12).filter(|&n| sel.selects(n, 0)).collect::<Vec<_>>();
+        assert_eq!(selected, &[3, 4, 10]);
+        assert!(!sel.is_done(9, 0));
+        assert!(sel.is_done(10, 0));
+
+        let sel = RowSelection { skip: 1, rows: None, limit: Some(2) };
+        assert!(!sel.selects(1, 0));
+        assert!(sel.selects(2, 0));
+        assert!(!sel.selects(4, 2));
+        assert!(!sel.is_done(3, 1));
+        assert!(sel.is_done(3, 2));
+    }
+}
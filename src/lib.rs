@@ -1,36 +1,238 @@
 use crate::Extract::*;
-use clap::{App, Arg};
-use csv::{ReaderBuilder, StringRecord};
+pub use crate::column::Column;
+pub use crate::filter::{Predicate, RowSelection};
+pub use crate::output::{HeaderMode, OutputFormat, Quoting, RecordWriter};
+pub use crate::select::{FieldList, FieldSpec, SubSelect};
+pub use crate::dialect::Dialect;
+pub use crate::template::Template;
+use clap::{App, Arg, ErrorKind};
+use csv::ByteRecord;
+use memchr::{memchr, memchr_iter};
+use memmap2::Mmap;
+use regex::bytes::Regex;
+use serde_json::Value;
 use std::{
+    borrow::Cow,
+    env,
     error::Error,
+    ffi::OsString,
+    fmt,
     fs::File,
-    io::{self, BufRead, BufReader},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    mem,
     ops::Range,
+    slice,
     str,
+    thread,
 };
 
-type MyResult<T> = Result<T, Box<dyn Error>>;
+mod column;
+mod dialect;
+mod filter;
+mod output;
+mod select;
+mod sniff;
+mod template;
+
+type MyResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
 type PositionList = Vec<Range<usize>>;
 
+/// Size of the pieces a file is split into for `--threads`.
+const CHUNK_SIZE: usize = 4 << 20;
+
+/// Exit status when every input was processed successfully.
+pub const EXIT_OK: i32 = 0;
+/// Exit status when an input file could not be opened or read.
+pub const EXIT_IO: i32 = 1;
+/// Exit status for invalid command-line usage.
+pub const EXIT_USAGE: i32 = 2;
+/// Exit status when an input contained malformed data, e.g. a bad CSV record.
+pub const EXIT_DATA: i32 = 3;
+
+const EXIT_STATUS_HELP: &str = "EXIT STATUS:
+    0    all inputs were processed successfully
+    1    an input file could not be opened or read
+    2    invalid command-line usage
+    3    an input contained malformed data
+
+When several inputs fail, the highest status is returned.";
+
 #[derive(Debug)]
 pub enum Extract {
-    Fields(PositionList),
+    /// Field selectors, each optionally narrowed to a part of the field.
+    Fields(FieldList),
     Bytes(PositionList),
     Chars(PositionList),
+    /// JSON Pointers selecting values from JSON Lines input.
+    Json(Vec<String>),
+    /// A pattern matched against each line, and the capture groups to write.
+    Regex(Regex, Vec<usize>),
+}
+
+/// What to do with a line that does not match `--regex`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unmatched {
+    /// Drop the line silently.
+    Skip,
+    /// Drop the line and say so on stderr.
+    Warn,
+    /// Stop cutting the file with a data error.
+    Error,
+}
+
+impl Unmatched {
+    pub const NAMES: &'static [&'static str] = &["skip", "warn", "error"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "skip" => Some(Self::Skip),
+            "warn" => Some(Self::Warn),
+            "error" => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with a record whose number of fields differs from that of the
+/// first record of its file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ragged {
+    /// Cut the record like any other.
+    Allow,
+    /// Cut the record and say so on stderr.
+    Warn,
+    /// Stop cutting the file with a data error.
+    Error,
+}
+
+impl Ragged {
+    pub const NAMES: &'static [&'static str] = &["allow", "warn", "error"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "allow" => Some(Self::Allow),
+            "warn" => Some(Self::Warn),
+            "error" => Some(Self::Error),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Config {
     files: Vec<String>,
-    delimiter: u8,
+    dialect: Dialect,
+    /// Whether to guess the delimiter and quote of each file (`--delim auto`).
+    sniff: bool,
+    /// When fields are quoted in the text output.
+    quoting: Quoting,
+    /// Written between output fields in place of the input delimiter.
+    output_delimiter: Option<String>,
     extract: Extract,
+    strict: bool,
+    threads: usize,
+    format: OutputFormat,
+    header: Option<HeaderMode>,
+    with_filename: bool,
+    line_number: bool,
+    prefix_sep: String,
+    filters: Vec<Predicate>,
+    /// Columns written after the selected fields.
+    columns: Vec<Column>,
+    /// Lays out the output fields of each record in the text format.
+    template: Option<Template>,
+    rows: RowSelection,
+    unmatched: Unmatched,
+    verbose: bool,
+    /// Written in place of each selected field, byte or character that a
+    /// record is too short to have.
+    fill: Option<String>,
+    /// Whether a record too short for the selected fields is an error.
+    strict_fields: bool,
+    ragged: Ragged,
+}
+
+impl Config {
+    /// Returns a writer that formats records for this configuration.
+    pub fn writer<W: Write>(&self, out: W) -> RecordWriter<W> {
+        let mut buf = [0; 4];
+        let delimiter = match &self.output_delimiter {
+            Some(delimiter) => delimiter.as_bytes(),
+            None => self.dialect.delimiter.encode_utf8(&mut buf).as_bytes(),
+        };
+        RecordWriter::new(out, self.format, delimiter)
+            .quoting(self.quoting, self.dialect.quote, self.dialect.output_escape())
+            .header(self.header)
+            .prefix(self.with_filename, self.line_number, self.prefix_sep.as_bytes())
+    }
+
+    /// Whether the records of a file can be cut in independent chunks.
+    fn is_splittable(&self) -> bool {
+        self.format.is_per_record()
+            && self.header.is_none()
+            && !self.line_number
+            && !self.filters.iter().any(Predicate::is_named)
+            && self.rows.is_all()
+            && !self.sniff
+            && !self.strict_fields
+            && !(matches!(self.extract, Fields(_)) && self.ragged == Ragged::Warn)
+            && !matches!(self.extract, Json(_))
+            && !(matches!(self.extract, Regex(..)) && self.unmatched != Unmatched::Skip)
+    }
+}
+
+/// An error raised while processing a single input file.
+#[derive(Debug)]
+struct FileError {
+    filename: String,
+    source: Box<dyn Error + Send + Sync>,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.filename, self.source)
+    }
+}
+
+impl Error for FileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// Maps an error returned by [`run`] to one of the `EXIT_*` statuses.
+pub fn exit_code(err: &(dyn Error + 'static)) -> i32 {
+    if let Some(e) = err.downcast_ref::<FileError>() {
+        exit_code(e.source.as_ref())
+    } else if err.is::<io::Error>() {
+        EXIT_IO
+    } else if let Some(e) = err.downcast_ref::<csv::Error>() {
+        if e.is_io_error() {
+            EXIT_IO
+        } else {
+            EXIT_DATA
+        }
+    } else {
+        EXIT_DATA
+    }
 }
 
 pub fn get_args() -> MyResult<Config> {
+    get_args_from(env::args_os())
+}
+
+/// Parses a `Config` from an explicit argument list, the first item being the
+/// program name.
+pub fn get_args_from<I, T>(args: I) -> MyResult<Config>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
     let matches = App::new("cutr")
         .version("0.1.0")
         .author("Ken C.Y. Leung <kenleung5e28@gmail.com>")
         .about("Rust cut")
+        .after_help(EXIT_STATUS_HELP)
         .arg(
             Arg::with_name("files")
             .value_name("FILE")
@@ -43,9 +245,86 @@ pub fn get_args() -> MyResult<Config> {
             .value_name("DELIMITER")
             .short("d")
             .long("delim")
-            .help("Field delimiter")
+            .help("Field delimiter: a character or an escape such as \\t, \\x1f or \\u{2192}, \
+                or \"auto\" to guess it from the first records of each file; lines are split on \
+                a non-ASCII delimiter without quoting")
             .default_value("\t")
         )
+        .arg(
+            Arg::with_name("delimiter_set")
+            .value_name("SET")
+            .long("delim-set")
+            .help("Split fields on any of the characters of SET, without quoting, in place of --delim")
+            .takes_value(true)
+            .conflicts_with_all(&["bytes", "chars", "json_lines", "regex", "quote", "escape", "no_double_quote"])
+        )
+        .arg(
+            Arg::with_name("output_delimiter")
+            .value_name("DELIMITER")
+            .long("output-delim")
+            .help("Delimiter written between output fields [default: the input delimiter, \
+                or the first character of --delim-set]")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("quote")
+            .value_name("CHAR")
+            .long("quote")
+            .help("Quote character of fields [default: \"]")
+            .takes_value(true)
+            .conflicts_with_all(&["bytes", "chars", "json_lines", "regex"])
+        )
+        .arg(
+            Arg::with_name("escape")
+            .value_name("CHAR")
+            .long("escape")
+            .help("Character escaping quotes in quoted fields")
+            .takes_value(true)
+            .conflicts_with_all(&["bytes", "chars", "json_lines", "regex"])
+        )
+        .arg(
+            Arg::with_name("no_double_quote")
+            .long("no-double-quote")
+            .help("Do not read two quotes in a quoted field as one")
+            .conflicts_with_all(&["bytes", "chars", "json_lines", "regex"])
+        )
+        .arg(
+            Arg::with_name("comment")
+            .value_name("CHAR")
+            .long("comment")
+            .help("Ignore records starting with CHAR")
+            .takes_value(true)
+            .conflicts_with_all(&["bytes", "chars", "json_lines", "regex"])
+        )
+        .arg(
+            Arg::with_name("trim")
+            .long("trim")
+            .help("Trim leading and trailing whitespace from fields")
+            .conflicts_with_all(&["bytes", "chars", "json_lines", "regex"])
+        )
+        .arg(
+            Arg::with_name("raw_escape")
+            .value_name("CHAR")
+            .long("raw-escape")
+            .help("Split lines on the delimiter without quoting, except where it follows CHAR")
+            .takes_value(true)
+            .conflicts_with_all(&["bytes", "chars", "json_lines", "regex", "quote", "escape", "no_double_quote"])
+        )
+        .arg(
+            Arg::with_name("unescape")
+            .long("unescape")
+            .help("Decode the escape sequences of --raw-escape fields as PostgreSQL COPY does, e.g. \\t \
+                as a tab, \\011 or \\x09 as well and \\\\ as \\, with a field of just \\N (NULL) left empty")
+            .requires("raw_escape")
+        )
+        .arg(
+            Arg::with_name("output_quoting")
+            .value_name("WHEN")
+            .long("output-quoting")
+            .help("Quote the fields of the text output with --quote and --escape")
+            .possible_values(Quoting::NAMES)
+            .default_value("never")
+        )
         .arg(
             Arg::with_name("bytes")
             .value_name("BYTES")
@@ -69,65 +348,822 @@ pub fn get_args() -> MyResult<Config> {
             .value_name("FIELDS")
             .short("f")
             .long("fields")
-            .help("Selected fields")
+            .help("Selected fields; NAME or NAME-NAME selects fields by header name, ignoring case \
+                and surrounding whitespace, /RE/ selects the fields whose header matches RE, !ITEM \
+                excludes fields, N.L, N.bL or N[D]L select chars, bytes or D-separated parts \
+                of field N, and ITEM:NAME renames a field in header rows")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("json_lines")
+            .long("json-lines")
+            .help("Parse each input line as JSON; --fields selects keys or JSON Pointers")
+            .conflicts_with_all(&["bytes", "chars"])
+            .requires("fields")
+        )
+        .arg(
+            Arg::with_name("regex")
+            .value_name("PATTERN")
+            .long("regex")
+            .help("Match each line against PATTERN; --fields selects capture groups by number or name")
+            .takes_value(true)
+            .conflicts_with_all(&["bytes", "chars", "json_lines", "header", "where"])
+        )
+        .arg(
+            Arg::with_name("unmatched")
+            .value_name("ACTION")
+            .long("unmatched")
+            .help("What to do with lines that do not match --regex")
+            .possible_values(Unmatched::NAMES)
+            .default_value("skip")
+        )
+        .arg(
+            Arg::with_name("output_format")
+            .value_name("FORMAT")
+            .long("output-format")
+            .help("Output format")
+            .possible_values(OutputFormat::NAMES)
+            .default_value("text")
+        )
+        .arg(
+            Arg::with_name("where")
+            .value_name("FIELD OP VALUE")
+            .long("where")
+            .help("Only cut records whose FIELD (number or header name) compares with VALUE \
+                by OP: = or != as text, <, <=, > or >= as a number, ~ or !~ as a regex")
+            .multiple(true)
+            .number_of_values(1)
+            .conflicts_with_all(&["bytes", "chars", "json_lines"])
+        )
+        .arg(
+            Arg::with_name("add_column")
+            .value_name("NAME=EXPR")
+            .long("add-column")
+            .help("Add a column NAME after the selected fields, holding EXPR: $FILENAME, $LINE \
+                (the line the record starts on), $NF (its number of fields) or a literal value")
+            .multiple(true)
+            .number_of_values(1)
+            .conflicts_with_all(&["bytes", "chars", "json_lines", "regex"])
+        )
+        .arg(
+            Arg::with_name("format")
+            .value_name("TEMPLATE")
+            .long("format")
+            .help("Write each record as TEMPLATE, where {N} or {NAME} stands for an output field by \
+                number or header name and {{ and }} for braces; selects every field without --fields")
+            .takes_value(true)
+            .conflicts_with_all(&["bytes", "chars", "json_lines", "regex"])
+        )
+        .arg(
+            Arg::with_name("rows")
+            .value_name("ROWS")
+            .long("rows")
+            .help("Selected records, numbered from 1 after any header")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("skip")
+            .value_name("N")
+            .long("skip")
+            .help("Skip the first N records of each file, after any header")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("limit")
+            .value_name("N")
+            .long("limit")
+            .help("Write at most N records of each file, besides any header")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("header")
+            .value_name("MODE")
+            .long("header")
+            .help("Treat the first record of each file as a header and keep, skip, or print it once")
+            .possible_values(HeaderMode::NAMES)
+            .conflicts_with("json_lines")
+        )
+        .arg(
+            Arg::with_name("with_filename")
+            .short("H")
+            .long("with-filename")
+            .help("Prefix each record with the name of its file")
+        )
+        .arg(
+            Arg::with_name("line_number")
+            .short("n")
+            .long("line-number")
+            .help("Prefix each record with its 1-based record number within its file")
+        )
+        .arg(
+            Arg::with_name("prefix_sep")
+            .value_name("SEP")
+            .long("prefix-sep")
+            .help("Separator written after the filename and record number prefixes")
+            .default_value(":")
+        )
+        .arg(
+            Arg::with_name("threads")
+            .value_name("N")
+            .long("threads")
+            .help("Number of threads used to cut regular files")
+            .default_value("1")
+        )
+        .arg(
+            Arg::with_name("fill")
+            .value_name("VALUE")
+            .long("fill")
+            .help("Write VALUE for each selected field, byte or character missing from a record")
             .takes_value(true)
+            .conflicts_with_all(&["json_lines", "regex"])
+        )
+        .arg(
+            Arg::with_name("strict_fields")
+            .long("strict-fields")
+            .help("Fail on a record that does not have every selected field")
+            .conflicts_with_all(&["fill", "bytes", "chars", "json_lines", "regex"])
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("ragged")
+            .value_name("ACTION")
+            .long("ragged")
+            .help("What to do with records whose field count differs from the first record's \
+                [default: error, or allow with --fill or --strict-fields]")
+            .possible_values(Ragged::NAMES)
+            .conflicts_with_all(&["bytes", "chars", "json_lines", "regex"])
+        )
+        .arg(
+            Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("Report the delimiter and quote chosen by --delim auto on stderr")
+        )
+        .arg(
+            Arg::with_name("strict")
+            .long("strict")
+            .help("Abort at the first input that cannot be processed")
+        )
+        .get_matches_from_safe(args)
+        .map_err(|e| match e.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => e.exit(),
+            _ => e,
+        })?;
     let delimiter = matches.value_of("delimiter").unwrap();
-    if delimiter.len() != 1 {
-        return Err(From::from(format!("--delim \"{}\" must be a single byte", delimiter)));
+    let sniff = delimiter == "auto";
+    // --delim has a default, so clap cannot tell it conflicts with --delim-set.
+    if matches.occurrences_of("delimiter") > 0 && matches.is_present("delimiter_set") {
+        return Err(From::from("--delim cannot be used with --delim-set"));
+    }
+    let delimiter_set = matches.value_of("delimiter_set")
+        .map_or(Ok(vec![]), |set| {
+            char_tokens(set).into_iter().map(|c| parse_byte("--delim-set", c)).collect::<MyResult<Vec<_>>>()
+        })?;
+    let dialect = Dialect {
+        delimiter: match delimiter_set.first() {
+            Some(&b) => char::from(b),
+            None if sniff => '\t',
+            None => parse_char("--delim", delimiter)?,
+        },
+        delimiter_set,
+        quote: matches.value_of("quote").map_or(Ok(b'"'), |c| parse_byte("--quote", c))?,
+        escape: matches.value_of("escape").map(|c| parse_byte("--escape", c)).transpose()?,
+        double_quote: !matches.is_present("no_double_quote"),
+        comment: matches.value_of("comment").map(|c| parse_byte("--comment", c)).transpose()?,
+        trim: matches.is_present("trim"),
+        raw_escape: matches.value_of("raw_escape").map(|c| parse_byte("--raw-escape", c)).transpose()?,
+        unescape: matches.is_present("unescape"),
+    };
+    if dialect.raw_escape.is_some_and(|c| char::from(c) == dialect.delimiter || dialect.delimiter_set.contains(&c)) {
+        return Err(From::from("--raw-escape must differ from --delim"));
     }
+    if !dialect.delimiter.is_ascii()
+        && ["quote", "escape", "no_double_quote"].iter().any(|&arg| matches.is_present(arg))
+    {
+        return Err(From::from(
+            "--quote, --escape and --no-double-quote need an ASCII --delim, as lines are split on \
+            a non-ASCII delimiter without quoting",
+        ));
+    }
+    let output_delimiter = matches.value_of("output_delimiter")
+        .map(|value| char_tokens(value).into_iter().map(|c| parse_char("--output-delim", c)).collect())
+        .transpose()?;
+    let threads = matches.value_of("threads").unwrap();
+    let threads = match threads.parse::<usize>() {
+        Ok(n) if n > 0 => n,
+        _ => return Err(From::from(format!("--threads \"{}\" must be a positive integer", threads))),
+    };
+    let rows = RowSelection {
+        skip: matches.value_of("skip").map_or(Ok(0), |n| parse_count("--skip", n))?,
+        rows: matches.value_of("rows").map(parse_pos).transpose()?,
+        limit: matches.value_of("limit").map(|n| parse_count("--limit", n)).transpose()?,
+    };
     let extract = if matches.is_present("bytes") {
         Bytes(parse_pos(matches.value_of("bytes").unwrap())?)
     } else if matches.is_present("chars") {
         Chars(parse_pos(matches.value_of("chars").unwrap())?)
+    } else if matches.is_present("json_lines") {
+        Json(parse_pointers(matches.value_of("fields").unwrap())?)
+    } else if let Some(pattern) = matches.value_of("regex") {
+        let re = Regex::new(pattern)?;
+        let groups = match matches.value_of("fields") {
+            Some(list) => parse_groups(&re, list)?,
+            None => (1..re.captures_len()).collect(),
+        };
+        Regex(re, groups)
     } else if matches.is_present("fields") {
-        Fields(parse_pos(matches.value_of("fields").unwrap())?)
+        Fields(FieldList::parse(matches.value_of("fields").unwrap())?)
+    } else if matches.is_present("format") {
+        Fields(FieldList::all())
     } else {
         return Err(From::from("Must have --fields, --bytes, or --chars"));
     };
+    let format = OutputFormat::from_name(matches.value_of("output_format").unwrap()).unwrap();
+    let template = matches.value_of("format").map(Template::parse).transpose()?;
+    if template.is_some() && format != OutputFormat::Text {
+        return Err(From::from("--format requires --output-format text"));
+    }
     Ok(Config {
         files: matches.values_of_lossy("files").unwrap(),
-        delimiter: delimiter.bytes().nth(0).unwrap(),
+        dialect,
+        sniff,
+        quoting: Quoting::from_name(matches.value_of("output_quoting").unwrap()).unwrap(),
+        output_delimiter,
         extract,
+        strict: matches.is_present("strict"),
+        threads,
+        format,
+        header: matches.value_of("header").and_then(HeaderMode::from_name),
+        with_filename: matches.is_present("with_filename"),
+        line_number: matches.is_present("line_number"),
+        prefix_sep: matches.value_of("prefix_sep").unwrap().to_string(),
+        filters: matches.values_of("where")
+            .map_or(Ok(vec![]), |exprs| exprs.map(Predicate::parse).collect())?,
+        columns: matches.values_of("add_column")
+            .map_or(Ok(vec![]), |specs| specs.map(Column::parse).collect())?,
+        template,
+        rows,
+        unmatched: Unmatched::from_name(matches.value_of("unmatched").unwrap()).unwrap(),
+        verbose: matches.is_present("verbose"),
+        fill: matches.value_of("fill").map(str::to_string),
+        strict_fields: matches.is_present("strict_fields"),
+        ragged: match matches.value_of("ragged") {
+            Some(name) => Ragged::from_name(name).unwrap(),
+            None if matches.is_present("fill") || matches.is_present("strict_fields") => Ragged::Allow,
+            None => Ragged::Error,
+        },
+    })
+}
+
+/// Parses a single character, given as is or as one of the escapes `\t`,
+/// `\n`, `\r`, `\0`, `\\`, `\xHH` (up to `\x7f`) and `\u{HHHH}`. A lone
+/// backslash stands for itself.
+fn parse_char(name: &str, value: &str) -> MyResult<char> {
+    let error = || format!("{} \"{}\" must be a single character", name, value);
+    let c = match value.strip_prefix('\\') {
+        None => {
+            let mut chars = value.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(From::from(error())),
+            }
+        }
+        Some("") | Some("\\") => '\\',
+        Some("t") => '\t',
+        Some("n") => '\n',
+        Some("r") => '\r',
+        Some("0") => '\0',
+        Some(escape) => escape.strip_prefix('x')
+            .filter(|hex| hex.len() == 2)
+            .or_else(|| escape.strip_prefix("u{")?.strip_suffix('}'))
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .filter(|&n| !escape.starts_with('x') || n < 0x80)
+            .and_then(char::from_u32)
+            .ok_or_else(error)?,
+    };
+    Ok(c)
+}
+
+/// Splits `value` into its characters and escapes, each to be parsed by
+/// [`parse_char`].
+fn char_tokens(value: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut rest = value;
+    while !rest.is_empty() {
+        let len = if !rest.starts_with('\\') {
+            rest.chars().next().map_or(0, char::len_utf8)
+        } else if rest.starts_with("\\u{") {
+            rest.find('}').map_or(rest.len(), |i| i + 1)
+        } else {
+            let chars = if rest.starts_with("\\x") { 4 } else { 2 };
+            rest.char_indices().nth(chars).map_or(rest.len(), |(i, _)| i)
+        };
+        let (token, tail) = rest.split_at(len);
+        tokens.push(token);
+        rest = tail;
+    }
+    tokens
+}
+
+/// Parses a single ASCII character, as [`parse_char`] does.
+fn parse_byte(name: &str, value: &str) -> MyResult<u8> {
+    match parse_char(name, value) {
+        Ok(c) if c.is_ascii() => Ok(c as u8),
+        _ => Err(From::from(format!("{} \"{}\" must be a single byte", name, value))),
+    }
+}
+
+fn parse_count(name: &str, value: &str) -> MyResult<usize> {
+    value.parse().map_err(|_| {
+        From::from(format!("{} \"{}\" must be a non-negative integer", name, value))
     })
 }
 
-pub fn run(config: Config) -> MyResult<()> {
-    for filename in config.files {
-        match open(&filename) {
-            Err(e) => eprintln!("{}: {}", filename, e),
-            Ok(file) => {
-                match &config.extract {
-                    Fields(field_pos) => {
-                        let mut reader = ReaderBuilder::new()
-                            .has_headers(false)
-                            .delimiter(config.delimiter)
-                            .from_reader(file);
-                        for record in reader.records() {
-                            let fields = extract_fields(&record?, field_pos);
-                            println!("{}", fields.join(str::from_utf8(&[config.delimiter])?));
+/// Processes every input file and returns the exit status of the run.
+///
+/// A file that cannot be processed is reported on stderr and the remaining
+/// files are still processed, unless `--strict` is given, in which case the
+/// first failure is returned as an error. A closed stdout (e.g. `cutr ... |
+/// head`) ends the run quietly.
+pub fn run(config: Config) -> MyResult<i32> {
+    let stdout = io::stdout();
+    let mut out = config.writer(BufWriter::new(stdout.lock()));
+    let mut status = EXIT_OK;
+    for filename in &config.files {
+        out.start_file(filename);
+        if let Err(e) = cut_file(&config, filename, &mut out) {
+            if is_broken_pipe(e.as_ref()) {
+                return Ok(status);
+            }
+            let err = FileError {
+                filename: filename.clone(),
+                source: e,
+            };
+            if config.strict {
+                finish(out).or_else(ignore_broken_pipe)?;
+                return Err(Box::new(err));
+            }
+            eprintln!("{}", err);
+            status = status.max(exit_code(&err));
+        }
+    }
+    finish(out).or_else(ignore_broken_pipe)?;
+    Ok(status)
+}
+
+fn finish<W: Write>(out: RecordWriter<W>) -> io::Result<()> {
+    out.finish()?.flush()
+}
+
+fn is_broken_pipe(err: &(dyn Error + 'static)) -> bool {
+    matches!(err.downcast_ref::<io::Error>(), Some(e) if e.kind() == io::ErrorKind::BrokenPipe)
+}
+
+fn ignore_broken_pipe(e: io::Error) -> io::Result<()> {
+    match e.kind() {
+        io::ErrorKind::BrokenPipe => Ok(()),
+        _ => Err(e),
+    }
+}
+
+fn cut_file<W: Write>(config: &Config, filename: &str, out: &mut RecordWriter<W>) -> MyResult<()> {
+    if config.threads > 1 && filename != "-" && config.is_splittable() {
+        cut_parallel(config, filename, CHUNK_SIZE, out)
+    } else {
+        cut(config, open(filename)?, out)
+    }
+}
+
+/// Cuts a regular file by mapping it into memory, splitting it into chunks of
+/// about `chunk_size` bytes at line boundaries and cutting up to
+/// `config.threads` chunks at a time.
+///
+/// Output is written in the original order. Falls back to sequential
+/// processing for anything that is not a regular file, and for field input
+/// containing quotes, where a quoted newline does not end a record.
+fn cut_parallel<W: Write>(
+    config: &Config,
+    filename: &str,
+    chunk_size: usize,
+    out: &mut RecordWriter<W>,
+) -> MyResult<()> {
+    let file = File::open(filename)?;
+    if !file.metadata()?.is_file() {
+        return cut(config, BufReader::new(file), out);
+    }
+    // SAFETY: the map is only read from. As with any tool mapping its input,
+    // the file must not be truncated by another process while it is cut.
+    let data = unsafe { Mmap::map(&file)? };
+    if matches!(config.extract, Fields(_))
+        && !config.dialect.is_raw()
+        && memchr(config.dialect.quote, &data).is_some()
+    {
+        return cut(config, &data[..], out);
+    }
+    let data = &data[..];
+    let chunks = split_chunks(data, chunk_size);
+    let lines = lines_before(&chunks);
+    let mut start = 0;
+    for (batch, lines) in chunks.chunks(config.threads).zip(lines.chunks(config.threads)) {
+        let results = thread::scope(|s| {
+            let workers = batch.iter()
+                .scan(start, |start, &chunk| {
+                    let before = &data[..*start];
+                    *start += chunk.len();
+                    Some((before, chunk))
+                })
+                .zip(lines)
+                .map(|((before, chunk), &lines)| s.spawn(move || {
+                    let mut buf = config.writer(vec![]);
+                    buf.start_file(filename);
+                    let res = cut_chunk(config, chunk, before, lines, &mut buf);
+                    (buf, res)
+                }))
+                .collect::<Vec<_>>();
+            workers.into_iter()
+                .map(|w| w.join().expect("cutr worker thread panicked"))
+                .collect::<Vec<_>>()
+        });
+        start += batch.iter().map(|chunk| chunk.len()).sum::<usize>();
+        for (mut buf, res) in results {
+            out.get_mut().write_all(buf.get_mut())?;
+            res?;
+        }
+    }
+    Ok(())
+}
+
+/// Splits `data` into pieces of at least `size` bytes that end on a newline,
+/// except possibly the last one.
+fn split_chunks(data: &[u8], size: usize) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let end = match rest.get(size..).and_then(|tail| memchr(b'\n', tail)) {
+            Some(i) => size + i + 1,
+            None => rest.len(),
+        };
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+/// Returns the number of lines in the file before each of `chunks`, the
+/// consecutive pieces of the file.
+fn lines_before(chunks: &[&[u8]]) -> Vec<usize> {
+    chunks.iter()
+        .scan(0, |lines, chunk| {
+            let before = *lines;
+            *lines += memchr_iter(b'\n', chunk).count();
+            Some(before)
+        })
+        .collect()
+}
+
+/// Extracts the selected parts of every record read from `file` into `out`.
+///
+/// Records are read into buffers that are reused from one record to the next,
+/// and the extracted pieces are written to `out` as borrowed slices.
+pub fn cut<R, W>(config: &Config, file: R, out: &mut RecordWriter<W>) -> MyResult<()>
+where
+    R: BufRead,
+    W: Write,
+{
+    cut_chunk(config, file, &[], 0, out)
+}
+
+/// Cuts `file`, which follows `before` in its file when cutting in chunks.
+/// `before` gives the field count of the first record, and `lines_before`
+/// the number of lines it holds.
+fn cut_chunk<R, W>(
+    config: &Config,
+    file: R,
+    before: &[u8],
+    lines_before: usize,
+    out: &mut RecordWriter<W>,
+) -> MyResult<()>
+where
+    R: BufRead,
+    W: Write,
+{
+    let mut rows = Rows::new(config);
+    match &config.extract {
+        Fields(fields) => {
+            let mut file = file;
+            let mut sample = vec![];
+            let mut dialect = config.dialect.clone();
+            if config.sniff {
+                file.by_ref().take(sniff::SNIFF_BYTES).read_to_end(&mut sample)?;
+                dialect = sniff_dialect(config, &sample, out.filename());
+                if config.output_delimiter.is_none() {
+                    out.set_delimiter(dialect.delimiter.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+            }
+            // The field list and the expected field count come from the first
+            // record of the file.
+            let mut record = ByteRecord::new();
+            let mut expected = None;
+            let mut field_pos = vec![];
+            let fill = config.fill.as_ref().map(String::as_bytes);
+            if dialect.records(before).read(&mut record)? {
+                expected = Some(record.len());
+                field_pos = fields.resolve(&record)?;
+                set_template(config, out, &record, &field_pos, fill)?;
+            }
+            let line_of = |record: &ByteRecord| {
+                record.position().map_or(0, |p| p.line()) as usize + lines_before
+            };
+            let mut reader = dialect.records(sample.as_slice().chain(file));
+            let mut filter_pos = vec![];
+            let mut values = vec![String::new(); config.columns.len()];
+            // The first selected field that a record of `len` fields lacks.
+            let missing = |field_pos: &[FieldSpec], len: usize| {
+                field_pos.iter()
+                    .filter(|spec| spec.range.end > len)
+                    .map(|spec| spec.range.start.max(len))
+                    .min()
+            };
+            while reader.read(&mut record)? {
+                if expected.is_none() {
+                    field_pos = fields.resolve(&record)?;
+                    set_template(config, out, &record, &field_pos, fill)?;
+                }
+                if let Some(i) = missing(&field_pos, record.len()).filter(|_| config.strict_fields) {
+                    let line = line_of(&record);
+                    return Err(From::from(format!("line {}: missing field {}", line, i + 1)));
+                }
+                let expected = *expected.get_or_insert(record.len());
+                if record.len() != expected && config.ragged != Ragged::Allow {
+                    let msg = format!(
+                        "line {}: found {} fields, expected {}", line_of(&record), record.len(), expected
+                    );
+                    if config.ragged == Ragged::Error {
+                        return Err(From::from(msg));
+                    }
+                    eprintln!("{}: {}", out.filename(), msg);
+                }
+                if rows.number == 0 {
+                    filter_pos = config.filters.iter()
+                        .map(|p| p.resolve(&record))
+                        .collect::<MyResult<Vec<_>>>()?;
+                }
+                let row = rows.next(out);
+                let keep = match row {
+                    Row::Header => true,
+                    Row::Selected => config.filters.iter()
+                        .zip(&filter_pos)
+                        .all(|(p, &i)| p.matches(&record, i)),
+                    Row::Skipped => false,
+                };
+                if row == Row::Header {
+                    let names = config.columns.iter().map(|c| c.name.as_bytes());
+                    rows.write(out, row, extract_header(&record, &field_pos, fill).into_iter().chain(names))?;
+                } else if keep {
+                    let line = line_of(&record);
+                    for (column, value) in config.columns.iter().zip(&mut values) {
+                        value.clear();
+                        column.write_value(value, out.filename(), line, record.len());
+                    }
+                    let values = values.iter().map(|v| v.as_bytes());
+                    rows.write(out, row, extract_fields(&record, &field_pos, fill).chain(values))?;
+                }
+                if rows.is_done() {
+                    break;
+                }
+            }
+        }
+        Bytes(byte_pos) => {
+            let mut bytes = vec![];
+            for_each_line(file, |line| {
+                let row = rows.next(out);
+                if row != Row::Skipped {
+                    bytes.clear();
+                    for (b, r) in extract_bytes(line, byte_pos).zip(byte_pos) {
+                        bytes.extend_from_slice(b);
+                        if let Some(fill) = &config.fill {
+                            bytes.extend(fill.repeat(r.len() - b.len()).bytes());
                         }
                     }
-                    Bytes(byte_pos) => {
-                        for line in file.lines() {
-                            let bytes = extract_bytes(&line?, byte_pos);
-                            println!("{}", bytes);
+                    rows.write(out, row, [String::from_utf8_lossy(&bytes).as_bytes()])?;
+                }
+                Ok(!rows.is_done())
+            })?;
+        }
+        Chars(char_pos) => {
+            let mut bounds = vec![];
+            let mut chars = String::new();
+            for_each_line(file, |line| {
+                let row = rows.next(out);
+                if row != Row::Skipped {
+                    chars.clear();
+                    for (c, r) in extract_chars(str::from_utf8(line)?, char_pos, &mut bounds).zip(char_pos) {
+                        chars.push_str(c);
+                        if let Some(fill) = &config.fill {
+                            chars.push_str(&fill.repeat(r.len() - c.chars().count()));
                         }
                     }
-                    Chars(char_pos) => {
-                        for line in file.lines() {
-                            let chars = extract_chars(&line?, char_pos);
-                            println!("{}", chars);
+                    rows.write(out, row, [chars.as_bytes()])?;
+                }
+                Ok(!rows.is_done())
+            })?;
+        }
+        Json(pointers) => {
+            for_each_line(file, |line| {
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    rows.number += 1;
+                    return Ok(true);
+                }
+                let row = rows.next(out);
+                if row != Row::Skipped {
+                    let value = serde_json::from_slice::<Value>(line)
+                        .map_err(|e| format!("line {}: {}", rows.number, e))?;
+                    let values = extract_json(&value, pointers).collect::<Vec<_>>();
+                    rows.write(out, row, values.iter().map(|v| v.as_bytes()))?;
+                }
+                Ok(!rows.is_done())
+            })?;
+        }
+        Regex(re, groups) => {
+            let mut locs = re.capture_locations();
+            for_each_line(file, |line| {
+                let row = rows.next(out);
+                if row == Row::Skipped {
+                    return Ok(!rows.is_done());
+                }
+                if re.captures_read(&mut locs, line).is_some() {
+                    let captures = groups.iter()
+                        .map(|&g| locs.get(g).map_or(&b""[..], |(start, end)| &line[start..end]));
+                    rows.write(out, row, captures)?;
+                } else {
+                    match config.unmatched {
+                        Unmatched::Skip => {}
+                        Unmatched::Warn => {
+                            eprintln!("{}: line {}: no match for --regex", out.filename(), rows.number);
+                        }
+                        Unmatched::Error => {
+                            return Err(From::from(format!("line {}: no match for --regex", rows.number)));
                         }
                     }
                 }
-            }
+                Ok(!rows.is_done())
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the `--format` template, if any, against the output header of a
+/// file whose first record is `record` and sets it on `out`.
+fn set_template<W: Write>(
+    config: &Config,
+    out: &mut RecordWriter<W>,
+    record: &ByteRecord,
+    field_pos: &[FieldSpec],
+    fill: Option<&[u8]>,
+) -> MyResult<()> {
+    if let Some(template) = &config.template {
+        let mut header = ByteRecord::from(extract_header(record, field_pos, fill));
+        for column in &config.columns {
+            header.push_field(column.name.as_bytes());
         }
+        out.set_template(Some(template.resolve(&header)?));
     }
     Ok(())
 }
 
+/// Guesses the dialect of `filename` from `sample`, the start of the file,
+/// falling back to the `--delim` default, and reports it with `--verbose`.
+fn sniff_dialect(config: &Config, sample: &[u8], filename: &str) -> Dialect {
+    let dialect = sniff::sniff(sample, &config.dialect);
+    if config.verbose {
+        match dialect {
+            Some(Dialect { delimiter, quote, .. }) => eprintln!(
+                "{}: --delim auto: delimiter {:?}, quote {:?}",
+                filename, delimiter, char::from(quote)
+            ),
+            None => eprintln!(
+                "{}: --delim auto: no delimiter found, using {:?}",
+                filename, config.dialect.delimiter
+            ),
+        }
+    }
+    dialect.unwrap_or_else(|| config.dialect.clone())
+}
+
+/// What a record read from a file is to become.
+#[derive(Debug, PartialEq)]
+enum Row {
+    Header,
+    Selected,
+    Skipped,
+}
+
+/// Numbers the records of a file and tells the header row and the records
+/// selected by `--rows`, `--skip` and `--limit` apart.
+struct Rows<'a> {
+    config: &'a Config,
+    header: bool,
+    /// Number of records read, the header included.
+    number: usize,
+    /// Number of data records read.
+    data: usize,
+    /// Number of data records written.
+    written: usize,
+}
+
+impl<'a> Rows<'a> {
+    fn new(config: &'a Config) -> Self {
+        Rows {
+            config,
+            header: config.header.is_some(),
+            number: 0,
+            data: 0,
+            written: 0,
+        }
+    }
+
+    /// Counts the next record and passes its number on to `out`.
+    fn next<W: Write>(&mut self, out: &mut RecordWriter<W>) -> Row {
+        self.number += 1;
+        out.set_number(self.number);
+        if mem::take(&mut self.header) {
+            return Row::Header;
+        }
+        self.data += 1;
+        if self.config.rows.selects(self.data, self.written) {
+            Row::Selected
+        } else {
+            Row::Skipped
+        }
+    }
+
+    /// Writes a record that was not skipped.
+    fn write<'r, W, I>(&mut self, out: &mut RecordWriter<W>, row: Row, fields: I) -> io::Result<()>
+    where
+        W: Write,
+        I: IntoIterator<Item = &'r [u8]>,
+    {
+        match row {
+            Row::Header => out.write_header(fields),
+            _ => {
+                self.written += 1;
+                out.write_record(fields)
+            }
+        }
+    }
+
+    /// Whether no later record of the file can be written.
+    fn is_done(&self) -> bool {
+        !self.header && self.config.rows.is_done(self.data, self.written)
+    }
+}
+
+/// Calls `f` with every line of `reader`, without its line terminator, until
+/// `f` returns `false`.
+///
+/// Lines are located with `memchr` and passed straight out of the reader's
+/// buffer; only a line straddling two buffer fills is copied.
+fn for_each_line<R, F>(mut reader: R, mut f: F) -> MyResult<()>
+where
+    R: BufRead,
+    F: FnMut(&[u8]) -> MyResult<bool>,
+{
+    let mut partial = vec![];
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            if !partial.is_empty() {
+                f(trim_eol(&partial))?;
+            }
+            return Ok(());
+        }
+        let mut start = 0;
+        for end in memchr_iter(b'\n', buf) {
+            let more = if partial.is_empty() {
+                f(trim_eol(&buf[start..end]))?
+            } else {
+                partial.extend_from_slice(&buf[start..end]);
+                let more = f(trim_eol(&partial))?;
+                partial.clear();
+                more
+            };
+            if !more {
+                return Ok(());
+            }
+            start = end + 1;
+        }
+        partial.extend_from_slice(&buf[start..]);
+        let len = buf.len();
+        reader.consume(len);
+    }
+}
+
+fn trim_eol(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
 fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
     match filename {
         "-" => Ok(Box::new(BufReader::new(io::stdin()))),
@@ -136,86 +1172,182 @@ fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
 }
 
 fn parse_pos(range: &str) -> MyResult<PositionList> {
-    let value_error = |v: &str| -> Box<dyn Error> {
-        From::from(format!("illegal list value: \"{}\"", v))
-    };
     if range.is_empty() {
         return Err(From::from("position lists cannot be empty"));
     }
-    let parts = range.split(",").collect::<Vec<_>>();
-    let mut list: PositionList = vec![];
-    for part in parts {
-        if part.is_empty() {
-            return Err(value_error(range));
-        }
-        let interval = part.split("-").collect::<Vec<_>>();
-        if interval.len() > 2 {
-            return Err(value_error(range));
-        }
-        let bounds = interval.into_iter()
-            .map(|endpoint| if endpoint.starts_with("+") {
-                Err(value_error(part))
-            } else {
-                let bound = endpoint.parse::<usize>().map_err(|_| value_error(part))?;
-                if bound == 0 {
-                    Err(value_error("0"))
-                } else {
-                    Ok(bound)
-                }
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-        let lower = bounds[0];
-        if bounds.len() == 1 {
-            list.push(lower - 1..lower);
+    range.split(',')
+        .map(|part| parse_range(part, range))
+        .collect()
+}
+
+/// Parses `part`, an `N` or `N-M` item of the position list `range`.
+fn parse_range(part: &str, range: &str) -> MyResult<Range<usize>> {
+    let value_error = |v: &str| -> Box<dyn Error + Send + Sync> {
+        From::from(format!("illegal list value: \"{}\"", v))
+    };
+    if part.is_empty() {
+        return Err(value_error(range));
+    }
+    let interval = part.split('-').collect::<Vec<_>>();
+    if interval.len() > 2 {
+        return Err(value_error(range));
+    }
+    let bounds = interval.into_iter()
+        .map(|endpoint| if endpoint.starts_with('+') {
+            Err(value_error(part))
         } else {
-            let upper = bounds[1];
-            if upper == 0 {
-                return Err(value_error("0"));
-            }
-            if lower >= upper {
-                return Err(From::from(format!("First number in range ({}) must be lower than second number ({})", lower, upper)));
+            let bound = endpoint.parse::<usize>().map_err(|_| value_error(part))?;
+            if bound == 0 {
+                Err(value_error("0"))
+            } else {
+                Ok(bound)
             }
-            list.push(lower - 1..upper);
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let lower = bounds[0];
+    if bounds.len() == 1 {
+        Ok(lower - 1..lower)
+    } else {
+        let upper = bounds[1];
+        if upper == 0 {
+            return Err(value_error("0"));
+        }
+        if lower >= upper {
+            return Err(From::from(format!("First number in range ({}) must be lower than second number ({})", lower, upper)));
         }
+        Ok(lower - 1..upper)
+    }
+}
+
+/// Parses a comma-separated list of top-level keys and JSON Pointers (those
+/// starting with `/`) into JSON Pointers.
+fn parse_pointers(list: &str) -> MyResult<Vec<String>> {
+    if list.is_empty() {
+        return Err(From::from("key lists cannot be empty"));
     }
-    Ok(list)
+    list.split(',')
+        .map(|key| match key {
+            "" => Err(From::from(format!("illegal key list: \"{}\"", list))),
+            _ if key.starts_with('/') => Ok(key.to_string()),
+            _ => Ok(format!("/{}", key.replace('~', "~0").replace('/', "~1"))),
+        })
+        .collect()
 }
 
-fn extract_chars(line: &str, char_pos: &[Range<usize>]) -> String {
-    let chars = line.chars().collect::<Vec<_>>();
-    char_pos.iter()
-        .cloned()
-        .flat_map(|r| {
-            r.filter_map(|i| chars.get(i))
+/// Parses a comma-separated list of capture group numbers and names of `re`
+/// into group numbers.
+fn parse_groups(re: &Regex, list: &str) -> MyResult<Vec<usize>> {
+    if list.is_empty() {
+        return Err(From::from("group lists cannot be empty"));
+    }
+    list.split(',')
+        .map(|group| {
+            let index = match group.parse::<usize>() {
+                Ok(n) => Some(n).filter(|&n| n < re.captures_len()),
+                Err(_) => re.capture_names().position(|name| name == Some(group)),
+            };
+            index.ok_or_else(|| From::from(format!("no capture group \"{}\" in --regex", group)))
         })
         .collect()
 }
 
-fn extract_bytes(line: &str, byte_pos: &[Range<usize>]) -> String {
-    let bytes = line.as_bytes();
-    let extracted = byte_pos.iter()
-        .cloned()
-        .flat_map(|r| {
-            r.filter_map(|i| bytes.get(i)).copied()
+/// Looks up every pointer in `value`. Strings are returned as they are, other
+/// values as JSON text and missing values as empty strings.
+fn extract_json<'a>(
+    value: &'a Value,
+    pointers: &'a [String],
+) -> impl Iterator<Item = Cow<'a, str>> {
+    pointers.iter().map(move |p| match value.pointer(p) {
+        Some(Value::String(s)) => Cow::Borrowed(s.as_str()),
+        Some(v) => Cow::Owned(v.to_string()),
+        None => Cow::Borrowed(""),
+    })
+}
+
+fn extract_chars<'a>(
+    line: &'a str,
+    char_pos: &'a [Range<usize>],
+    bounds: &'a mut Vec<usize>,
+) -> impl Iterator<Item = &'a str> {
+    let max_end = char_pos.iter().map(|r| r.end).max().unwrap_or(0);
+    bounds.clear();
+    bounds.extend(line.char_indices().map(|(i, _)| i).take(max_end + 1));
+    if bounds.len() <= max_end {
+        bounds.push(line.len());
+    }
+    let bounds: &'a [usize] = bounds;
+    let last = bounds.len() - 1;
+    char_pos.iter().map(move |r| {
+        &line[bounds[r.start.min(last)]..bounds[r.end.min(last)]]
+    })
+}
+
+fn extract_bytes<'a>(
+    line: &'a [u8],
+    byte_pos: &'a [Range<usize>],
+) -> impl Iterator<Item = &'a [u8]> {
+    byte_pos.iter().map(move |r| {
+        &line[r.start.min(line.len())..r.end.min(line.len())]
+    })
+}
+
+/// Extracts the selected fields of `record`, writing `fill` for each field
+/// the record is too short to have, or leaving such fields out.
+fn extract_fields<'a>(
+    record: &'a ByteRecord,
+    field_pos: &'a [FieldSpec],
+    fill: Option<&'a [u8]>,
+) -> impl Iterator<Item = &'a [u8]> {
+    field_pos.iter()
+        .flat_map(move |spec| {
+            spec.range.clone()
+                .filter_map(move |i| {
+                    record.get(i)
+                        .map(|f| spec.sub.as_ref().map_or(f, |sub| sub.select(f)))
+                        .or(fill)
+                })
         })
-        .collect::<Vec<_>>();
-    String::from_utf8_lossy(&extracted).into_owned()
 }
 
-fn extract_fields(record: &StringRecord, field_pos: &[Range<usize>]) -> Vec<String> {
+/// Extracts the fields of the header row `record` as [`extract_fields`]
+/// does, naming the renamed fields by their new names.
+fn extract_header<'a>(
+    record: &'a ByteRecord,
+    field_pos: &'a [FieldSpec],
+    fill: Option<&'a [u8]>,
+) -> Vec<&'a [u8]> {
     field_pos.iter()
-        .cloned()
-        .flat_map(|r| {
-            r.filter_map(|i| record.get(i))
+        .flat_map(|spec| match &spec.name {
+            Some(name) => vec![name.as_bytes()],
+            None => extract_fields(record, slice::from_ref(spec), fill).collect(),
         })
-        .map(String::from)
         .collect()
 }
 
 #[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
 mod unit_tests {
-    use csv::StringRecord;
-    use super::{parse_pos, extract_chars, extract_bytes, extract_fields};
+    use csv::ByteRecord;
+    use std::ops::Range;
+    use super::{parse_pos, parse_char, char_tokens, extract_chars, extract_bytes, extract_fields, for_each_line, split_chunks, lines_before,
+        parse_pointers, extract_json, parse_groups, cut, cut_parallel, get_args_from, FieldSpec, SubSelect};
+    use regex::bytes::Regex;
+
+    fn chars(line: &str, char_pos: &[Range<usize>]) -> String {
+        extract_chars(line, char_pos, &mut vec![]).collect()
+    }
+
+    fn bytes(line: &str, byte_pos: &[Range<usize>]) -> String {
+        let bytes = extract_bytes(line.as_bytes(), byte_pos).collect::<Vec<_>>();
+        String::from_utf8_lossy(&bytes.concat()).into_owned()
+    }
+
+    fn fields(record: &ByteRecord, field_pos: &[Range<usize>]) -> Vec<String> {
+        let field_pos = field_pos.iter().cloned().map(FieldSpec::from).collect::<Vec<_>>();
+        extract_fields(record, &field_pos, None)
+            .map(|f| String::from_utf8_lossy(f).into_owned())
+            .collect()
+    }
 
     #[test]
     fn test_parse_pos() {
@@ -332,31 +1464,197 @@ mod unit_tests {
 
     #[test]
     fn test_extract_chars() {
-        assert_eq!(extract_chars("", &[0..1]), "".to_string());
-        assert_eq!(extract_chars("ábc", &[0..1]), "á".to_string());
-        assert_eq!(extract_chars("ábc", &[0..1, 2..3]), "ác".to_string());
-        assert_eq!(extract_chars("ábc", &[0..3]), "ábc".to_string());
-        assert_eq!(extract_chars("ábc", &[2..3, 1..2]), "cb".to_string());
-        assert_eq!(extract_chars("ábc", &[0..1, 1..2, 4..5]), "áb".to_string());
+        assert_eq!(chars("", &[0..1]), "".to_string());
+        assert_eq!(chars("ábc", &[0..1]), "á".to_string());
+        assert_eq!(chars("ábc", &[0..1, 2..3]), "ác".to_string());
+        assert_eq!(chars("ábc", &[0..3]), "ábc".to_string());
+        assert_eq!(chars("ábc", &[2..3, 1..2]), "cb".to_string());
+        assert_eq!(chars("ábc", &[0..1, 1..2, 4..5]), "áb".to_string());
     }
 
     #[test]
     fn test_extract_bytes() {
-        assert_eq!(extract_bytes("ábc", &[0..1]), "�".to_string());
-        assert_eq!(extract_bytes("ábc", &[0..2]), "á".to_string());
-        assert_eq!(extract_bytes("ábc", &[0..3]), "áb".to_string());
-        assert_eq!(extract_bytes("ábc", &[0..4]), "ábc".to_string());
-        assert_eq!(extract_bytes("ábc", &[3..4, 2..3]), "cb".to_string());
-        assert_eq!(extract_bytes("ábc", &[0..2, 5..6]), "á".to_string());
+        assert_eq!(bytes("ábc", &[0..1]), "�".to_string());
+        assert_eq!(bytes("ábc", &[0..2]), "á".to_string());
+        assert_eq!(bytes("ábc", &[0..3]), "áb".to_string());
+        assert_eq!(bytes("ábc", &[0..4]), "ábc".to_string());
+        assert_eq!(bytes("ábc", &[3..4, 2..3]), "cb".to_string());
+        assert_eq!(bytes("ábc", &[0..2, 5..6]), "á".to_string());
+    }
+
+    #[test]
+    fn test_parse_char() {
+        let parse = |value| parse_char("--delim", value).map_err(|e| e.to_string());
+        assert_eq!(parse(","), Ok(','));
+        assert_eq!(parse("→"), Ok('→'));
+        assert_eq!(parse("\\"), Ok('\\'));
+        assert_eq!(parse("\\\\"), Ok('\\'));
+        assert_eq!(parse("\\t"), Ok('\t'));
+        assert_eq!(parse("\\0"), Ok('\0'));
+        assert_eq!(parse("\\x1f"), Ok('\x1f'));
+        assert_eq!(parse("\\u{2192}"), Ok('→'));
+        assert_eq!(parse(""), Err("--delim \"\" must be a single character".to_string()));
+        assert!(parse(",,").is_err());
+        assert!(parse("\\x1").is_err());
+        assert!(parse("\\xff").is_err());
+        assert!(parse("\\u{d800}").is_err());
+        assert!(parse("\\u{+20}").is_err());
+        assert!(parse("\\u2192").is_err());
+        assert!(parse("\\q").is_err());
+    }
+
+    #[test]
+    fn test_char_tokens() {
+        assert_eq!(char_tokens(""), Vec::<&str>::new());
+        assert_eq!(char_tokens(",;|"), &[",", ";", "|"]);
+        assert_eq!(char_tokens("\\t→\\x1f\\u{2192}\\\\"), &["\\t", "→", "\\x1f", "\\u{2192}", "\\\\"]);
+        assert_eq!(char_tokens("a\\x1é\\"), &["a", "\\x1é", "\\"]);
     }
 
     #[test]
     fn test_extract_fields() {
-        let rec = StringRecord::from(vec!["Captain", "Sham", "12345"]);
-        assert_eq!(extract_fields(&rec, &[0..1]), &["Captain"]);
-        assert_eq!(extract_fields(&rec, &[1..2]), &["Sham"]);
-        assert_eq!(extract_fields(&rec, &[0..1, 2..3]), &["Captain", "12345"]);
-        assert_eq!(extract_fields(&rec, &[0..1, 3..4]), &["Captain"]);
-        assert_eq!(extract_fields(&rec, &[1..2, 0..1]), &["Sham", "Captain"]);
+        let rec = ByteRecord::from(vec!["Captain", "Sham", "12345"]);
+        assert_eq!(fields(&rec, &[0..1]), &["Captain"]);
+        assert_eq!(fields(&rec, &[1..2]), &["Sham"]);
+        assert_eq!(fields(&rec, &[0..1, 2..3]), &["Captain", "12345"]);
+        assert_eq!(fields(&rec, &[0..1, 3..4]), &["Captain"]);
+        assert_eq!(fields(&rec, &[1..2, 0..1]), &["Sham", "Captain"]);
+
+        let field_pos = [FieldSpec { range: 0..3, sub: Some(SubSelect::Chars(0..3)), name: None }];
+        let subs = extract_fields(&rec, &field_pos, None).collect::<Vec<_>>();
+        assert_eq!(subs, [&b"Cap"[..], b"Sha", b"123"]);
+
+        let field_pos = [FieldSpec::from(1..2), FieldSpec::from(3..5)];
+        let filled = extract_fields(&rec, &field_pos, Some(b"-")).collect::<Vec<_>>();
+        assert_eq!(filled, [&b"Sham"[..], b"-", b"-"]);
+
+        let field_pos = [
+            FieldSpec { range: 1..2, sub: Some(SubSelect::Chars(1..2)), name: None },
+            FieldSpec { range: 3..4, sub: Some(SubSelect::Chars(1..2)), name: None },
+            FieldSpec { range: 4..5, sub: Some(SubSelect::Fields("-".to_string(), 1..2)), name: None },
+        ];
+        let filled = extract_fields(&rec, &field_pos, Some(b"XYZ")).collect::<Vec<_>>();
+        assert_eq!(filled, [&b"h"[..], b"XYZ", b"XYZ"]);
+    }
+
+    #[test]
+    fn test_for_each_line() {
+        let read = |input: &[u8], capacity: usize| {
+            let mut lines = vec![];
+            let reader = std::io::BufReader::with_capacity(capacity, input);
+            for_each_line(reader, |line| {
+                lines.push(String::from_utf8_lossy(line).into_owned());
+                Ok(true)
+            })
+            .unwrap();
+            lines
+        };
+        assert_eq!(read(b"", 8), Vec::<String>::new());
+        assert_eq!(read(b"a\nbc\r\n\nd", 8), &["a", "bc", "", "d"]);
+        assert_eq!(read(b"abcdefgh\nijklmnopqrst\n", 3), &["abcdefgh", "ijklmnopqrst"]);
+    }
+
+    #[test]
+    fn test_split_chunks() {
+        assert!(split_chunks(b"", 4).is_empty());
+        assert_eq!(split_chunks(b"ab\ncd\n", 100), vec![&b"ab\ncd\n"[..]]);
+        assert_eq!(
+            split_chunks(b"ab\ncd\nef\ngh", 2),
+            vec![&b"ab\n"[..], b"cd\n", b"ef\n", b"gh"]
+        );
+        assert_eq!(
+            split_chunks(b"abcdef\ng\nhi\n", 4),
+            vec![&b"abcdef\n"[..], b"g\nhi\n"]
+        );
+    }
+
+    #[test]
+    fn test_lines_before() {
+        assert!(lines_before(&[]).is_empty());
+        let data = b"a\nb\nc\nd\n\n\nefg\nh";
+        let chunks = split_chunks(data, 3);
+        assert_eq!(chunks, vec![&b"a\nb\n"[..], b"c\nd\n", b"\n\nefg\n", b"h"]);
+        assert_eq!(lines_before(&chunks), &[0, 2, 4, 7]);
+    }
+
+    #[test]
+    fn test_cut_parallel() {
+        let path = std::env::temp_dir().join(format!("cutr-test-{}.csv", std::process::id()));
+        let mut data = "id,name\n".to_string();
+        for i in 1..300 {
+            data.push_str(&format!("{},n{}\n", i, i % 7));
+        }
+        std::fs::write(&path, &data).unwrap();
+        let filename = path.to_str().unwrap();
+        // Cuts the file in one go and in chunks of 64 bytes, four at a time.
+        let cut_both = |args: &[&str]| {
+            let data = std::fs::read(filename).unwrap();
+            let config = get_args_from(["cutr", "-d", ",", "--threads", "4"].iter().chain(args).chain([&filename]))
+                .unwrap();
+            let mut outputs = vec![];
+            for parallel in [false, true] {
+                let mut out = config.writer(vec![]);
+                out.start_file(filename);
+                let res = match parallel {
+                    false => cut(&config, data.as_slice(), &mut out),
+                    true => cut_parallel(&config, filename, 64, &mut out),
+                };
+                let text = String::from_utf8(out.finish().unwrap()).unwrap();
+                outputs.push((text, res.err().map(|e| e.to_string())));
+            }
+            outputs
+        };
+        let outputs = cut_both(&["-f", "name,1", "--add-column", "line=$LINE"]);
+        assert_eq!(outputs[0], outputs[1]);
+        let lines = outputs[1].0.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 300);
+        assert_eq!(lines[0], "name,id,1");
+        assert_eq!(lines[299], "n5,299,300");
+
+        data.insert_str(data.find("\n250,").unwrap() + 1, "ragged,row,here\n");
+        std::fs::write(&path, &data).unwrap();
+        let outputs = cut_both(&["-f", "2"]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[1].0.lines().count(), 250);
+        assert_eq!(outputs[1].1.as_deref(), Some("line 251: found 3 fields, expected 2"));
+    }
+
+    #[test]
+    fn test_parse_pointers() {
+        assert!(parse_pointers("").is_err());
+        assert!(parse_pointers("a,").is_err());
+        assert_eq!(parse_pointers("status").unwrap(), &["/status"]);
+        assert_eq!(
+            parse_pointers("a/b,m~n,/request/status").unwrap(),
+            &["/a~1b", "/m~0n", "/request/status"]
+        );
+    }
+
+    #[test]
+    fn test_extract_json() {
+        let value = serde_json::json!({
+            "user": "ken",
+            "a/b": 1,
+            "request": {"status": 200, "tags": ["x", "y"], "body": null},
+        });
+        let pointers = parse_pointers("user,a/b,/request/status,/request/tags,/request/tags/1,/request/body,missing").unwrap();
+        assert_eq!(
+            extract_json(&value, &pointers).collect::<Vec<_>>(),
+            &["ken", "1", "200", r#"["x","y"]"#, "y", "null", ""]
+        );
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let re = Regex::new(r"(\d+)ms .* user=(?P<user>\w+)").unwrap();
+        assert!(parse_groups(&re, "").is_err());
+        assert!(parse_groups(&re, "3").is_err());
+        assert_eq!(
+            parse_groups(&re, "x").unwrap_err().to_string(),
+            "no capture group \"x\" in --regex"
+        );
+        assert_eq!(parse_groups(&re, "user,1").unwrap(), &[2, 1]);
+        assert_eq!(parse_groups(&re, "0").unwrap(), &[0]);
     }
 }
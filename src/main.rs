@@ -1,6 +1,16 @@
 fn main() {
-    if let Err(e) = cutr::get_args().and_then(cutr::run) {
-        eprintln!("{}", e);
-        std::process::exit(1);
-    }
+    let code = match cutr::get_args() {
+        Err(e) => {
+            eprintln!("{}", e);
+            cutr::EXIT_USAGE
+        }
+        Ok(config) => match cutr::run(config) {
+            Ok(code) => code,
+            Err(e) => {
+                eprintln!("{}", e);
+                cutr::exit_code(e.as_ref())
+            }
+        },
+    };
+    std::process::exit(code);
 }
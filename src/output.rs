@@ -0,0 +1,621 @@
+use crate::template::{Chunk, Template};
+use memchr::memmem;
+use std::io::{self, Write};
+use unicode_width::UnicodeWidthStr;
+
+/// How extracted records are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Fields joined by the delimiter, one record per line.
+    Text,
+    /// A single JSON array holding one array per record.
+    Json,
+    /// One JSON array per line.
+    Ndjson,
+    /// Columns padded to a common display width.
+    Table,
+    /// A GitHub-flavoured Markdown table headed by the first header row
+    /// written, or by column numbers if there is none.
+    Markdown,
+}
+
+impl OutputFormat {
+    pub const NAMES: &'static [&'static str] = &["text", "json", "ndjson", "table", "markdown"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            "ndjson" => Some(Self::Ndjson),
+            "table" => Some(Self::Table),
+            "markdown" => Some(Self::Markdown),
+            _ => None,
+        }
+    }
+
+    /// Whether the output of a record does not depend on any other record.
+    pub fn is_per_record(self) -> bool {
+        matches!(self, Self::Text | Self::Ndjson)
+    }
+}
+
+/// What to do with the first record of every input file, the header row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderMode {
+    /// Write the header of every file.
+    Keep,
+    /// Drop the header of every file.
+    Skip,
+    /// Write the header of the first file only.
+    Once,
+}
+
+impl HeaderMode {
+    pub const NAMES: &'static [&'static str] = &["keep", "skip", "once"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "keep" => Some(Self::Keep),
+            "skip" => Some(Self::Skip),
+            "once" => Some(Self::Once),
+            _ => None,
+        }
+    }
+}
+
+/// When the fields written in the text format are quoted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quoting {
+    /// Write every field as it is.
+    Never,
+    /// Quote the fields holding the delimiter, the quote or a line break.
+    Necessary,
+    /// Quote every field.
+    Always,
+}
+
+impl Quoting {
+    pub const NAMES: &'static [&'static str] = &["never", "necessary", "always"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "never" => Some(Self::Never),
+            "necessary" => Some(Self::Necessary),
+            "always" => Some(Self::Always),
+            _ => None,
+        }
+    }
+}
+
+/// Writes extracted records to `W` in an [`OutputFormat`].
+///
+/// The writer keeps the state needed to produce one well-formed document
+/// across all input files, so [`RecordWriter::finish`] must be called once
+/// every record has been written. Tables are buffered until then, since every
+/// row is needed to know the width of the columns.
+///
+/// In the JSON formats, header rows are not written as records but name the
+/// keys of the objects written for the records that follow them.
+pub struct RecordWriter<W: Write> {
+    out: W,
+    format: OutputFormat,
+    delimiter: Vec<u8>,
+    quoting: Quoting,
+    quote: u8,
+    escape: Option<u8>,
+    template: Option<Template>,
+    header: Option<HeaderMode>,
+    with_filename: bool,
+    with_number: bool,
+    prefix_sep: Vec<u8>,
+    filename: String,
+    number: usize,
+    headers: usize,
+    keys: Vec<String>,
+    records: usize,
+    rows: Vec<Vec<String>>,
+    /// Whether the first of `rows` is a header row.
+    header_first: bool,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(out: W, format: OutputFormat, delimiter: &[u8]) -> Self {
+        RecordWriter {
+            out,
+            format,
+            delimiter: delimiter.to_vec(),
+            quoting: Quoting::Never,
+            quote: b'"',
+            escape: None,
+            template: None,
+            header: None,
+            with_filename: false,
+            with_number: false,
+            prefix_sep: vec![],
+            filename: String::new(),
+            number: 0,
+            headers: 0,
+            keys: vec![],
+            records: 0,
+            rows: vec![],
+            header_first: false,
+        }
+    }
+
+    /// Sets how the header rows passed to [`RecordWriter::write_header`] are
+    /// written.
+    pub fn header(mut self, header: Option<HeaderMode>) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Sets when fields are quoted in the text format, with `quote`. A quote
+    /// inside a field is preceded by `escape`, or doubled if there is none.
+    pub fn quoting(mut self, quoting: Quoting, quote: u8, escape: Option<u8>) -> Self {
+        self.quoting = quoting;
+        self.quote = quote;
+        self.escape = escape;
+        self
+    }
+
+    /// Prefixes every record with the name of its file and/or its record
+    /// number, followed by `sep` in the text format. The other formats write
+    /// the prefixes as leading fields.
+    pub fn prefix(mut self, filename: bool, number: bool, sep: &[u8]) -> Self {
+        self.with_filename = filename;
+        self.with_number = number;
+        self.prefix_sep = sep.to_vec();
+        self
+    }
+
+    /// Names the file the following records come from.
+    pub fn start_file(&mut self, filename: &str) {
+        self.filename.clear();
+        self.filename.push_str(filename);
+    }
+
+    /// Returns the name given to [`RecordWriter::start_file`].
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Sets the delimiter joining the fields of the following records in the
+    /// text format.
+    pub fn set_delimiter(&mut self, delimiter: &[u8]) {
+        self.delimiter.clear();
+        self.delimiter.extend_from_slice(delimiter);
+    }
+
+    /// Sets the template laying out the fields of the following records in
+    /// the text format in place of the delimiter.
+    pub fn set_template(&mut self, template: Option<Template>) {
+        self.template = template;
+    }
+
+    /// Sets the 1-based number, within its file, of the next record written.
+    pub fn set_number(&mut self, number: usize) {
+        self.number = number;
+    }
+
+    /// Returns the underlying writer, for output that is already formatted.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    /// Writes the header row of an input file, as the [`HeaderMode`] allows.
+    pub fn write_header<'a, I>(&mut self, fields: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        self.headers += 1;
+        let written = match self.header {
+            Some(HeaderMode::Keep) => true,
+            Some(HeaderMode::Once) => self.headers == 1,
+            _ => false,
+        };
+        match self.format {
+            OutputFormat::Json | OutputFormat::Ndjson => {
+                self.keys = self.header_row(fields);
+                Ok(())
+            }
+            OutputFormat::Table | OutputFormat::Markdown if written => {
+                let row = self.header_row(fields);
+                self.header_first |= self.rows.is_empty();
+                self.rows.push(row);
+                Ok(())
+            }
+            OutputFormat::Text if written => self.write_record(fields),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the header row made of `fields`, preceded by the names of the
+    /// prefix columns.
+    fn header_row<'a, I>(&self, fields: I) -> Vec<String>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut row = vec![];
+        if self.with_filename {
+            row.push("filename".to_string());
+        }
+        if self.with_number {
+            row.push("record".to_string());
+        }
+        row.extend(fields.into_iter().map(|f| String::from_utf8_lossy(f).into_owned()));
+        row
+    }
+
+    /// Writes one record made of `fields`.
+    pub fn write_record<'a, I>(&mut self, fields: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        if self.format == OutputFormat::Text {
+            if self.with_filename {
+                self.out.write_all(self.filename.as_bytes())?;
+                self.out.write_all(&self.prefix_sep)?;
+            }
+            if self.with_number {
+                write!(self.out, "{}", self.number)?;
+                self.out.write_all(&self.prefix_sep)?;
+            }
+            if let Some(template) = self.template.take() {
+                let fields = fields.into_iter().collect::<Vec<_>>();
+                let res = template.chunks(&fields).try_for_each(|chunk| match chunk {
+                    Chunk::Text(text) => self.out.write_all(text),
+                    Chunk::Field(field) => self.write_text_field(field),
+                });
+                self.template = Some(template);
+                res?;
+            } else {
+                for (i, field) in fields.into_iter().enumerate() {
+                    if i > 0 {
+                        self.out.write_all(&self.delimiter)?;
+                    }
+                    self.write_text_field(field)?;
+                }
+            }
+            self.out.write_all(b"\n")?;
+            self.records += 1;
+            return Ok(());
+        }
+        let number = self.number.to_string();
+        let mut all: Vec<&[u8]> = vec![];
+        if self.with_filename {
+            all.push(self.filename.as_bytes());
+        }
+        if self.with_number {
+            all.push(number.as_bytes());
+        }
+        for field in fields {
+            all.push(field);
+        }
+        let fields = all.into_iter();
+        let keys = self.header.map(|_| self.keys.as_slice());
+        match self.format {
+            OutputFormat::Json => {
+                self.out.write_all(if self.records == 0 { b"[\n" } else { b",\n" })?;
+                write_json_record(&mut self.out, keys, fields)?;
+            }
+            OutputFormat::Ndjson => {
+                write_json_record(&mut self.out, keys, fields)?;
+                self.out.write_all(b"\n")?;
+            }
+            _ => {
+                let row = fields.map(|f| String::from_utf8_lossy(f).into_owned()).collect();
+                self.rows.push(row);
+            }
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    fn write_text_field(&mut self, field: &[u8]) -> io::Result<()> {
+        let quoted = match self.quoting {
+            Quoting::Never => false,
+            Quoting::Necessary => {
+                field.iter().any(|&b| b == self.quote || b == b'\n' || b == b'\r')
+                    || memmem::find(field, &self.delimiter).is_some()
+            }
+            Quoting::Always => true,
+        };
+        if !quoted {
+            return self.out.write_all(field);
+        }
+        let escape = self.escape.unwrap_or(self.quote);
+        self.out.write_all(&[self.quote])?;
+        for part in field.split_inclusive(|&b| b == self.quote || b == escape) {
+            let (last, text) = part.split_last().unwrap();
+            if *last == self.quote || *last == escape {
+                self.out.write_all(text)?;
+                self.out.write_all(&[escape, *last])?;
+            } else {
+                self.out.write_all(part)?;
+            }
+        }
+        self.out.write_all(&[self.quote])
+    }
+
+    /// Completes the document and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.format {
+            OutputFormat::Json => {
+                self.out.write_all(if self.records == 0 { b"[]\n" } else { b"\n]\n" })?;
+            }
+            OutputFormat::Table => write_table(&mut self.out, &self.rows)?,
+            OutputFormat::Markdown => write_markdown(&mut self.out, &self.rows, self.header_first)?,
+            OutputFormat::Text | OutputFormat::Ndjson => {}
+        }
+        Ok(self.out)
+    }
+}
+
+fn column_widths<S: AsRef<str>>(rows: &[Vec<S>], min_width: usize) -> Vec<usize> {
+    let mut widths = vec![];
+    for row in rows {
+        if widths.len() < row.len() {
+            widths.resize(row.len(), min_width);
+        }
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.as_ref().width());
+        }
+    }
+    widths
+}
+
+fn write_padded<W: Write>(out: &mut W, cell: &str, width: usize) -> io::Result<()> {
+    write!(out, "{}{:pad$}", cell, "", pad = width.saturating_sub(cell.width()))
+}
+
+/// Writes `rows` with every column padded to its widest cell, separated by
+/// two spaces.
+fn write_table<W: Write>(out: &mut W, rows: &[Vec<String>]) -> io::Result<()> {
+    let widths = column_widths(rows, 0);
+    for row in rows {
+        let mut line = vec![];
+        for (i, &width) in widths.iter().enumerate() {
+            if i > 0 {
+                line.extend_from_slice(b"  ");
+            }
+            write_padded(&mut line, row.get(i).map_or("", String::as_str), width)?;
+        }
+        let len = line.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+        out.write_all(&line[..len])?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Writes `rows` as a Markdown table, using the first row as its header if
+/// `header_first` is set and numbering the columns otherwise.
+fn write_markdown<W: Write>(out: &mut W, rows: &[Vec<String>], header_first: bool) -> io::Result<()> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let mut rows = rows.iter()
+        .map(|row| row.iter().map(|cell| escape_markdown(cell)).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    if !header_first {
+        let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+        rows.insert(0, (1..=columns).map(|i| i.to_string()).collect());
+    }
+    let widths = column_widths(&rows, 3);
+    for (n, row) in rows.iter().enumerate() {
+        out.write_all(b"|")?;
+        for (i, &width) in widths.iter().enumerate() {
+            out.write_all(b" ")?;
+            write_padded(out, row.get(i).map_or("", String::as_str), width)?;
+            out.write_all(b" |")?;
+        }
+        out.write_all(b"\n")?;
+        if n == 0 {
+            out.write_all(b"|")?;
+            for &width in &widths {
+                write!(out, " {} |", "-".repeat(width))?;
+            }
+            out.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+fn escape_markdown(cell: &str) -> String {
+    cell.replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace(['\r', '\n'], "<br>")
+}
+
+/// Writes an object keyed by `keys` when there are any, and an array
+/// otherwise. Fields beyond the last key are keyed by their 1-based position.
+fn write_json_record<'a, W, I>(out: &mut W, keys: Option<&[String]>, fields: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    out.write_all(if keys.is_some() { b"{" } else { b"[" })?;
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        if let Some(keys) = keys {
+            match keys.get(i) {
+                Some(key) => write_json_string(out, key)?,
+                None => write_json_string(out, &(i + 1).to_string())?,
+            }
+            out.write_all(b":")?;
+        }
+        write_json_string(out, &String::from_utf8_lossy(field))?;
+    }
+    out.write_all(if keys.is_some() { b"}" } else { b"]" })
+}
+
+/// Writes `s` as a quoted JSON string.
+fn write_json_string<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    let bytes = s.as_bytes();
+    let mut start = 0;
+    for (i, &b) in bytes.iter().enumerate() {
+        let escape: &[u8] = match b {
+            b'"' => b"\\\"",
+            b'\\' => b"\\\\",
+            b'\n' => b"\\n",
+            b'\r' => b"\\r",
+            b'\t' => b"\\t",
+            0x08 => b"\\b",
+            0x0c => b"\\f",
+            0x00..=0x1f => b"",
+            _ => continue,
+        };
+        out.write_all(&bytes[start..i])?;
+        if escape.is_empty() {
+            write!(out, "\\u{:04x}", b)?;
+        } else {
+            out.write_all(escape)?;
+        }
+        start = i + 1;
+    }
+    out.write_all(&bytes[start..])?;
+    out.write_all(b"\"")
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::{escape_markdown, write_json_string, HeaderMode, OutputFormat, Quoting, RecordWriter};
+
+    fn json(s: &str) -> String {
+        let mut out = vec![];
+        write_json_string(&mut out, s).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn write(format: OutputFormat, records: &[&[&str]]) -> String {
+        write_with_header(format, None, records)
+    }
+
+    fn write_with_header(
+        format: OutputFormat,
+        header: Option<HeaderMode>,
+        records: &[&[&str]],
+    ) -> String {
+        let mut writer = RecordWriter::new(vec![], format, b",").header(header);
+        for record in records {
+            let fields = record.iter().map(|f| f.as_bytes());
+            if header.is_some() && record.first() == Some(&"title") {
+                writer.write_header(fields).unwrap();
+            } else {
+                writer.write_record(fields).unwrap();
+            }
+        }
+        String::from_utf8(writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_write_json_string() {
+        assert_eq!(json(""), r#""""#);
+        assert_eq!(json("Les Misérables"), r#""Les Misérables""#);
+        assert_eq!(json(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json("a\\b\tc\nd\re"), r#""a\\b\tc\nd\re""#);
+        assert_eq!(json("\x00\x08\x0c\x1f"), r#""\u0000\b\f\u001f""#);
+    }
+
+    #[test]
+    fn test_record_writer() {
+        let records: &[&[&str]] = &[&["a", "b"], &["c"]];
+        assert_eq!(write(OutputFormat::Text, records), "a,b\nc\n");
+        assert_eq!(write(OutputFormat::Json, records), "[\n[\"a\",\"b\"],\n[\"c\"]\n]\n");
+        assert_eq!(write(OutputFormat::Json, &[]), "[]\n");
+        assert_eq!(write(OutputFormat::Ndjson, records), "[\"a\",\"b\"]\n[\"c\"]\n");
+    }
+
+    #[test]
+    fn test_table() {
+        let records: &[&[&str]] = &[&["title", "year"], &["Les Misérables", "2012"], &["甲乙", "", "x"]];
+        assert_eq!(
+            write(OutputFormat::Table, records),
+            "title           year\nLes Misérables  2012\n甲乙                  x\n"
+        );
+        assert_eq!(
+            write_with_header(OutputFormat::Markdown, Some(HeaderMode::Keep), records),
+            "| title          | year |     |\n\
+             | -------------- | ---- | --- |\n\
+             | Les Misérables | 2012 |     |\n\
+             | 甲乙           |      | x   |\n"
+        );
+        assert_eq!(
+            write_with_header(OutputFormat::Markdown, Some(HeaderMode::Skip), records),
+            "| 1              | 2    | 3   |\n\
+             | -------------- | ---- | --- |\n\
+             | Les Misérables | 2012 |     |\n\
+             | 甲乙           |      | x   |\n"
+        );
+        assert_eq!(write(OutputFormat::Markdown, &[&["a"]]), "| 1   |\n| --- |\n| a   |\n");
+        assert_eq!(write(OutputFormat::Markdown, &[]), "");
+        assert_eq!(write(OutputFormat::Table, &[]), "");
+    }
+
+    #[test]
+    fn test_escape_markdown() {
+        assert_eq!(escape_markdown("a|b"), "a\\|b");
+        assert_eq!(escape_markdown("a\r\nb\nc"), "a<br>b<br>c");
+    }
+
+    #[test]
+    fn test_header() {
+        let records: &[&[&str]] = &[&["title", "year"], &["a", "1"], &["title", "year"], &["b", "2", "x"]];
+        let write = |format, header| write_with_header(format, Some(header), records);
+        assert_eq!(write(OutputFormat::Text, HeaderMode::Keep), "title,year\na,1\ntitle,year\nb,2,x\n");
+        assert_eq!(write(OutputFormat::Text, HeaderMode::Skip), "a,1\nb,2,x\n");
+        assert_eq!(write(OutputFormat::Text, HeaderMode::Once), "title,year\na,1\nb,2,x\n");
+        assert_eq!(
+            write(OutputFormat::Ndjson, HeaderMode::Keep),
+            "{\"title\":\"a\",\"year\":\"1\"}\n{\"title\":\"b\",\"year\":\"2\",\"3\":\"x\"}\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Json, HeaderMode::Skip),
+            "[\n{\"title\":\"a\",\"year\":\"1\"},\n{\"title\":\"b\",\"year\":\"2\",\"3\":\"x\"}\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_prefix() {
+        let write = |format, header| {
+            let mut writer = RecordWriter::new(vec![], format, b"\t")
+                .header(header)
+                .prefix(true, true, b":");
+            writer.start_file("a.tsv");
+            writer.set_number(1);
+            writer.write_header([&b"title"[..], b"year"]).unwrap();
+            writer.set_number(3);
+            writer.write_record([&b"Ran"[..], b"1985"]).unwrap();
+            String::from_utf8(writer.finish().unwrap()).unwrap()
+        };
+        assert_eq!(
+            write(OutputFormat::Text, Some(HeaderMode::Keep)),
+            "a.tsv:1:title\tyear\na.tsv:3:Ran\t1985\n"
+        );
+        assert_eq!(write(OutputFormat::Ndjson, None), "[\"a.tsv\",\"3\",\"Ran\",\"1985\"]\n");
+        assert_eq!(
+            write(OutputFormat::Ndjson, Some(HeaderMode::Skip)),
+            "{\"filename\":\"a.tsv\",\"record\":\"3\",\"title\":\"Ran\",\"year\":\"1985\"}\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Table, Some(HeaderMode::Keep)),
+            "filename  record  title  year\na.tsv     3       Ran    1985\n"
+        );
+    }
+
+    #[test]
+    fn test_quoting() {
+        let write = |quoting, escape| {
+            let mut writer = RecordWriter::new(vec![], OutputFormat::Text, b",")
+                .quoting(quoting, b'\'', escape);
+            writer.write_record([&b"a"[..], b"b,c", b"it's", b"x\\y"]).unwrap();
+            String::from_utf8(writer.finish().unwrap()).unwrap()
+        };
+        assert_eq!(write(Quoting::Never, None), "a,b,c,it's,x\\y\n");
+        assert_eq!(write(Quoting::Necessary, None), "a,'b,c','it''s',x\\y\n");
+        assert_eq!(write(Quoting::Always, None), "'a','b,c','it''s','x\\y'\n");
+        assert_eq!(write(Quoting::Always, Some(b'\\')), "'a','b,c','it\\'s','x\\\\y'\n");
+    }
+}
@@ -0,0 +1,572 @@
+use crate::{parse_range, MyResult};
+use csv::ByteRecord;
+use memchr::memmem;
+use regex::bytes::Regex;
+use std::{fmt, ops::Range};
+
+/// A selector in a field list: a range of fields, optionally narrowed to a
+/// part of each field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSpec {
+    pub range: Range<usize>,
+    pub sub: Option<SubSelect>,
+    /// Name written for the field in header rows instead of its header.
+    pub name: Option<String>,
+}
+
+/// The part of a field picked by a sub-selection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubSelect {
+    /// `F.L` or `F.cL`: characters `L` of the field.
+    Chars(Range<usize>),
+    /// `F.bL`: bytes `L` of the field.
+    Bytes(Range<usize>),
+    /// `F[D]L`: sub-fields `L` of the field, split on the character `D`.
+    Fields(String, Range<usize>),
+}
+
+impl From<Range<usize>> for FieldSpec {
+    fn from(range: Range<usize>) -> Self {
+        FieldSpec { range, sub: None, name: None }
+    }
+}
+
+impl SubSelect {
+    /// Returns the selected part of `field`. Selections always cover a
+    /// contiguous run of the field, possibly an empty one.
+    pub fn select<'a>(&self, field: &'a [u8]) -> &'a [u8] {
+        match self {
+            SubSelect::Bytes(r) => {
+                &field[r.start.min(field.len())..r.end.min(field.len())]
+            }
+            SubSelect::Chars(r) => {
+                // Bytes that are not UTF-8 continuation bytes start a char.
+                let mut starts = field.iter()
+                    .enumerate()
+                    .filter(|(_, &b)| b & 0xc0 != 0x80)
+                    .map(|(i, _)| i)
+                    .chain([field.len()]);
+                let start = starts.nth(r.start).unwrap_or(field.len());
+                let end = starts.nth(r.end - r.start - 1).unwrap_or(field.len());
+                &field[start..end]
+            }
+            SubSelect::Fields(delim, r) => {
+                let delim = delim.as_bytes();
+                let mut bounds = memmem::find_iter(field, delim)
+                    .map(|i| (i, i + delim.len()))
+                    .chain([(field.len(), field.len())]);
+                let start = match r.start {
+                    0 => 0,
+                    n => match bounds.nth(n - 1) {
+                        Some((_, after)) => after,
+                        None => return &[],
+                    },
+                };
+                let end = bounds.nth(r.end - r.start - 1).map_or(field.len(), |(at, _)| at);
+                &field[start..end]
+            }
+        }
+    }
+}
+
+/// A field list as given to `--fields`, resolved against the first record
+/// of each file into [`FieldSpec`]s.
+///
+/// Besides positions, an item may be a header name, a range `NAME-NAME` of
+/// the fields from one header name through another, or a regular expression
+/// `/RE/` selecting the fields whose header matches it. Names match headers
+/// regardless of case and surrounding whitespace, and may be followed by a
+/// sub-selection unless a header is exactly the whole item. An item
+/// selecting a single field may rename it in header rows with a `:NAME`
+/// suffix. An item starting with `!` excludes fields instead. A list of
+/// exclusions only selects all the other fields.
+#[derive(Debug)]
+pub struct FieldList {
+    items: Vec<Item>,
+}
+
+#[derive(Debug)]
+struct Item {
+    selector: Selector,
+    exclude: bool,
+    sub: Option<SubSelect>,
+    rename: Option<String>,
+}
+
+#[derive(Debug)]
+enum Selector {
+    Range(Range<usize>),
+    /// A header name or named range, and the same item read as a shorter
+    /// name followed by a sub-selection, if it can be.
+    Name(String, Option<(String, SubSelect)>),
+    Pattern(Regex),
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Selector::Range(range) if range.len() == 1 => write!(f, "{}", range.end),
+            Selector::Range(range) => write!(f, "{}-{}", range.start + 1, range.end),
+            Selector::Name(name, _) => write!(f, "{}", name),
+            Selector::Pattern(re) => write!(f, "/{}/", re),
+        }
+    }
+}
+
+impl FieldList {
+    pub fn parse(list: &str) -> MyResult<Self> {
+        if list.is_empty() {
+            return Err(From::from("position lists cannot be empty"));
+        }
+        let items = split_list(list)
+            .into_iter()
+            .map(|item| parse_item(item, list))
+            .collect::<MyResult<_>>()?;
+        Ok(FieldList { items })
+    }
+
+    /// Returns the list selecting every field.
+    pub fn all() -> Self {
+        FieldList { items: vec![] }
+    }
+
+    /// Returns the fields selected in records whose first record is
+    /// `header`.
+    pub fn resolve(&self, header: &ByteRecord) -> MyResult<Vec<FieldSpec>> {
+        let mut specs = vec![];
+        let mut excluded = vec![];
+        for item in &self.items {
+            let mut sub = item.sub.clone();
+            let positions = match &item.selector {
+                Selector::Range(range) => vec![range.clone()],
+                // The whole item is a name if a header is exactly that, and
+                // otherwise preferably a name with a sub-selection.
+                Selector::Name(name, Some((base, base_sub)))
+                    if !header.iter().any(|f| f == name.as_bytes()) =>
+                {
+                    match resolve_name(base, header)? {
+                        Some(range) => {
+                            sub = Some(base_sub.clone());
+                            vec![range]
+                        }
+                        None => resolve_name(name, header)?.into_iter().collect(),
+                    }
+                }
+                Selector::Name(name, _) => resolve_name(name, header)?.into_iter().collect(),
+                Selector::Pattern(re) => header.iter()
+                    .enumerate()
+                    .filter(|(_, f)| re.is_match(f))
+                    .map(|(i, _)| i..i + 1)
+                    .collect(),
+            };
+            if item.exclude {
+                excluded.extend(positions);
+                continue;
+            }
+            if positions.is_empty() {
+                return Err(match &item.selector {
+                    Selector::Name(name, _) => From::from(format!("--fields: no field named \"{}\"", name)),
+                    _ => From::from(format!("--fields: no field matches {}", item.selector)),
+                });
+            }
+            if let Some(name) = &item.rename {
+                let count = positions.iter().map(Range::len).sum::<usize>();
+                if count != 1 {
+                    return Err(From::from(format!(
+                        "--fields: {} selects {} fields and cannot be renamed to \"{}\"",
+                        item.selector, count, name
+                    )));
+                }
+            }
+            specs.extend(positions.into_iter().map(|range| FieldSpec {
+                range,
+                sub: sub.clone(),
+                name: item.rename.clone(),
+            }));
+        }
+        if self.items.iter().all(|item| item.exclude) {
+            specs.push(FieldSpec::from(0..header.len()));
+        }
+        if excluded.is_empty() {
+            return Ok(specs);
+        }
+        Ok(specs.into_iter()
+            .flat_map(|spec| {
+                let excluded = &excluded;
+                spec.range.clone()
+                    .filter(move |i| !excluded.iter().any(|r| r.contains(i)))
+                    .map(move |i| FieldSpec { range: i..i + 1, ..spec.clone() })
+            })
+            .collect())
+    }
+}
+
+/// Returns the fields of `header` selected by the name or named range
+/// `name`, or `None` if there are none. A name that could be read either as
+/// a header name or as a range, or in several ways as a range, is an error.
+fn resolve_name(name: &str, header: &ByteRecord) -> MyResult<Option<Range<usize>>> {
+    let mut found = vec![];
+    if let Some(i) = find_name("--fields", name, header)? {
+        found.push((name, i, i));
+    }
+    for (at, _) in name.match_indices('-') {
+        let (first, last) = (&name[..at], &name[at + 1..]);
+        if first.is_empty() || last.is_empty() {
+            continue;
+        }
+        let (start, end) = (find_name("--fields", first, header)?, find_name("--fields", last, header)?);
+        if let (Some(start), Some(end)) = (start, end) {
+            found.push((first, start, end));
+        }
+    }
+    match found[..] {
+        [] => Ok(None),
+        [(first, start, end)] if start > end => Err(From::from(format!(
+            "--fields: \"{}\" comes after \"{}\" in the header",
+            first,
+            &name[first.len() + 1..]
+        ))),
+        [(_, start, end)] => Ok(Some(start..end + 1)),
+        [(first, ..), ..] if first == name => Err(From::from(format!(
+            "--fields: \"{}\" is ambiguous: it names both a field and a range of fields",
+            name
+        ))),
+        _ => Err(From::from(format!(
+            "--fields: \"{}\" is ambiguous: it names several ranges of fields",
+            name
+        ))),
+    }
+}
+
+/// Returns the index of the field of `header` named `name`, preferring an
+/// exact match to one ignoring case and surrounding whitespace, or `None`
+/// if there is none. Several matches are an error of the option `option`.
+pub(crate) fn find_name(option: &str, name: &str, header: &ByteRecord) -> MyResult<Option<usize>> {
+    let exact = positions(header, |f| f == name.as_bytes());
+    if let [i] = exact[..] {
+        return Ok(Some(i));
+    }
+    let key = normalize(name.as_bytes());
+    let loose = positions(header, |f| normalize(f) == key);
+    match loose[..] {
+        [] => Ok(None),
+        [i] => Ok(Some(i)),
+        _ => Err(From::from(format!(
+            "{}: \"{}\" matches several fields: {}",
+            option,
+            name,
+            loose.iter().map(|i| (i + 1).to_string()).collect::<Vec<_>>().join(", ")
+        ))),
+    }
+}
+
+/// Returns the indices of the fields of `header` satisfying `predicate`.
+fn positions(header: &ByteRecord, predicate: impl Fn(&[u8]) -> bool) -> Vec<usize> {
+    header.iter()
+        .enumerate()
+        .filter(|(_, f)| predicate(f))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Returns a header name trimmed and lowercased for loose matching.
+fn normalize(name: &[u8]) -> String {
+    String::from_utf8_lossy(name).trim().to_lowercase()
+}
+
+/// Returns the index of the `/` ending the pattern at the start of `s`, the
+/// opening `/` excluded.
+fn pattern_end(s: &str) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'/' => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Splits a field list on the commas outside `[...]` and `/.../`.
+fn split_list(list: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let mut start = 0;
+    for i in separators(list, b',') {
+        items.push(&list[start..i]);
+        start = i + 1;
+    }
+    items.push(&list[start..]);
+    items
+}
+
+/// Returns the indices of the bytes `sep` of a field list, or of an item of
+/// one, that are outside `[...]` and `/.../`.
+fn separators(list: &str, sep: u8) -> Vec<usize> {
+    let bytes = list.as_bytes();
+    let mut found = vec![];
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b',' => {
+                if sep == b',' {
+                    found.push(i);
+                }
+                start = i + 1;
+            }
+            b if b == sep => found.push(i),
+            b'[' => {
+                // Skip the delimiter, which may itself be a comma.
+                i += 1;
+            }
+            b'/' if i == start || (i == start + 1 && bytes[start] == b'!') => {
+                i += pattern_end(&list[i + 1..]).map_or(bytes.len(), |end| end + 1);
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    found
+}
+
+/// Parses an item of the field list `list`: `[!]` followed by a position
+/// list item or a `/RE/` pattern, optionally followed by a sub-selection
+/// `.L`, `.cL`, `.bL` or `[D]L`, or by a header name or named range with an
+/// optional sub-selection, and then by an optional `:NAME` renaming the
+/// field.
+fn parse_item(item: &str, list: &str) -> MyResult<Item> {
+    let illegal = || format!("illegal list value: \"{}\"", item);
+    let (item, rename) = match separators(item, b':').last() {
+        Some(&at) if item[at + 1..].is_empty() || item.starts_with('!') => return Err(From::from(illegal())),
+        Some(&at) => (&item[..at], Some(item[at + 1..].to_string())),
+        None => (item, None),
+    };
+    let (exclude, rest) = match item.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, item),
+    };
+    let (selector, sub) = if let Some(pattern) = rest.strip_prefix('/') {
+        let end = pattern_end(pattern).ok_or_else(illegal)?;
+        (Selector::Pattern(Regex::new(&pattern[..end])?), &pattern[end + 1..])
+    } else if !rest.is_empty() && !rest.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+        // Exclusions take no sub-selection, so their names are never split.
+        let split = rest.match_indices(['.', '['])
+            .filter(|&(at, _)| at > 0 && !exclude)
+            .find_map(|(at, _)| Some((rest[..at].to_string(), parse_sub(&rest[at..], item).ok()?)));
+        (Selector::Name(rest.to_string(), split), "")
+    } else {
+        let at = rest.find(['.', '[']).unwrap_or(rest.len());
+        (Selector::Range(parse_range(&rest[..at], list)?), &rest[at..])
+    };
+    let sub = match sub {
+        "" => None,
+        _ if exclude => return Err(From::from(illegal())),
+        _ => Some(parse_sub(sub, item)?),
+    };
+    Ok(Item { selector, exclude, sub, rename })
+}
+
+/// Parses a sub-selection `.L`, `.cL`, `.bL` or `[D]L` of the field list
+/// item `item`.
+fn parse_sub(sub: &str, item: &str) -> MyResult<SubSelect> {
+    let illegal = || format!("illegal list value: \"{}\"", item);
+    if let Some(rest) = sub.strip_prefix('.') {
+        if let Some(bytes) = rest.strip_prefix('b') {
+            Ok(SubSelect::Bytes(parse_range(bytes, item)?))
+        } else {
+            Ok(SubSelect::Chars(parse_range(rest.strip_prefix('c').unwrap_or(rest), item)?))
+        }
+    } else {
+        let rest = sub.strip_prefix('[').ok_or_else(illegal)?;
+        let mut chars = rest.chars();
+        let delim = chars.next().ok_or_else(illegal)?;
+        let sub_range = chars.as_str().strip_prefix(']').ok_or_else(illegal)?;
+        Ok(SubSelect::Fields(delim.to_string(), parse_range(sub_range, item)?))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::{split_list, FieldList, FieldSpec, SubSelect};
+    use crate::MyResult;
+    use csv::ByteRecord;
+
+    fn parse_fields(list: &str) -> MyResult<Vec<FieldSpec>> {
+        resolve(list, &["a", "b", "c", "d", "e"])
+    }
+
+    fn resolve(list: &str, header: &[&str]) -> MyResult<Vec<FieldSpec>> {
+        FieldList::parse(list)?.resolve(&ByteRecord::from(header.to_vec()))
+    }
+
+    fn positions(list: &str, header: &[&str]) -> Vec<usize> {
+        resolve(list, header).unwrap()
+            .into_iter()
+            .flat_map(|spec| spec.range)
+            .collect()
+    }
+
+    #[test]
+    fn test_split_list() {
+        assert_eq!(split_list("1"), &["1"]);
+        assert_eq!(split_list("1,2-3,"), &["1", "2-3", ""]);
+        assert_eq!(split_list("1[,]2,3[[]1"), &["1[,]2", "3[[]1"]);
+        assert_eq!(split_list("/a,b/,!/\\/,/.1,2"), &["/a,b/", "!/\\/,/.1", "2"]);
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        assert!(parse_fields("").is_err());
+        assert!(parse_fields("1.").is_err());
+        assert!(parse_fields("1.0").is_err());
+        assert!(parse_fields("1.x").is_err());
+        assert!(parse_fields("1[").is_err());
+        assert!(parse_fields("1[:").is_err());
+        assert!(parse_fields("1[:2").is_err());
+        assert!(parse_fields("1[::]2").is_err());
+        assert_eq!(
+            parse_fields("1.2x").unwrap_err().to_string(),
+            "illegal list value: \"2x\""
+        );
+        assert_eq!(
+            parse_fields("1x.1").unwrap_err().to_string(),
+            "illegal list value: \"1x\""
+        );
+
+        assert_eq!(parse_fields("1,3-4").unwrap(), vec![FieldSpec::from(0..1), FieldSpec::from(2..4)]);
+        assert_eq!(
+            parse_fields("2.1-3,5.c4,1-2.b2,3[:]2,4[,]1-2").unwrap(),
+            vec![
+                FieldSpec { range: 1..2, sub: Some(SubSelect::Chars(0..3)), name: None },
+                FieldSpec { range: 4..5, sub: Some(SubSelect::Chars(3..4)), name: None },
+                FieldSpec { range: 0..2, sub: Some(SubSelect::Bytes(1..2)), name: None },
+                FieldSpec { range: 2..3, sub: Some(SubSelect::Fields(":".to_string(), 1..2)), name: None },
+                FieldSpec { range: 3..4, sub: Some(SubSelect::Fields(",".to_string(), 0..2)), name: None },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let header = ["id", "metric_a", "ssn", "metric_b", "dob"];
+        assert_eq!(positions("/^metric_/", &header), &[1, 3]);
+        assert_eq!(positions("1,/^metric_/,1", &header), &[0, 1, 3, 0]);
+        assert_eq!(positions("!ssn,!dob", &header), &[0, 1, 3]);
+        assert_eq!(positions("!/^metric_/,!1", &header), &[2, 4]);
+        assert_eq!(positions("1-4,!ssn", &header), &[0, 1, 3]);
+        assert_eq!(positions("!/a/,/a/", &header), Vec::<usize>::new());
+        assert_eq!(positions("!nothing", &header), &[0, 1, 2, 3, 4]);
+        assert_eq!(
+            resolve("/^m/.1-3", &header).unwrap(),
+            [
+                FieldSpec { range: 1..2, sub: Some(SubSelect::Chars(0..3)), name: None },
+                FieldSpec { range: 3..4, sub: Some(SubSelect::Chars(0..3)), name: None },
+            ]
+        );
+        assert_eq!(
+            resolve("/^x/", &header).unwrap_err().to_string(),
+            "--fields: no field matches /^x/"
+        );
+        assert!(FieldList::parse("/(/").is_err());
+        assert!(FieldList::parse("/a").is_err());
+        assert!(FieldList::parse("!").is_err());
+        assert!(FieldList::parse("!1.1").is_err());
+        assert_eq!(positions("!a.b", &["a", "a.b"]), &[0]);
+        assert!(FieldList::parse("!/a/.1").is_err());
+        assert_eq!(positions("ssn", &header), &[2]);
+
+        let header = ["Title", "year", "a.b", "a"];
+        assert_eq!(
+            resolve("title.1-4,Title[:]2,year.b2", &header).unwrap(),
+            [
+                FieldSpec { range: 0..1, sub: Some(SubSelect::Chars(0..4)), name: None },
+                FieldSpec { range: 0..1, sub: Some(SubSelect::Fields(":".to_string(), 1..2)), name: None },
+                FieldSpec { range: 1..2, sub: Some(SubSelect::Bytes(1..2)), name: None },
+            ]
+        );
+        assert_eq!(positions("a.b", &header), &[2]);
+        assert_eq!(
+            resolve("a.1", &header).unwrap(),
+            [FieldSpec { range: 3..4, sub: Some(SubSelect::Chars(0..1)), name: None }]
+        );
+        assert_eq!(
+            resolve("title.x", &header).unwrap_err().to_string(),
+            "--fields: no field named \"title.x\""
+        );
+    }
+
+    #[test]
+    fn test_resolve_names() {
+        let header = ["Title ", "year", "director", "e-mail", "notes"];
+        assert_eq!(positions("title,YEAR", &header), &[0, 1]);
+        assert_eq!(positions("title-director", &header), &[0, 1, 2]);
+        assert_eq!(positions(" year - e-mail ", &header), &[1, 2, 3]);
+        assert_eq!(positions("e-mail,year-year", &header), &[3, 1]);
+        assert_eq!(positions("!year-director", &header), &[0, 3, 4]);
+        assert_eq!(positions("Title ,title", &["Title ", "title"]), &[0, 1]);
+        assert_eq!(
+            resolve("director-title", &header).unwrap_err().to_string(),
+            "--fields: \"director\" comes after \"title\" in the header"
+        );
+        assert_eq!(
+            resolve("title", &["Title", " title"]).unwrap_err().to_string(),
+            "--fields: \"title\" matches several fields: 1, 2"
+        );
+        assert_eq!(
+            resolve("a-b", &["a-b", "a", "b"]).unwrap_err().to_string(),
+            "--fields: \"a-b\" is ambiguous: it names both a field and a range of fields"
+        );
+        assert_eq!(
+            resolve("a-b-c", &["a", "b-c", "a-b", "c"]).unwrap_err().to_string(),
+            "--fields: \"a-b-c\" is ambiguous: it names several ranges of fields"
+        );
+        assert_eq!(
+            resolve("title-rating", &header).unwrap_err().to_string(),
+            "--fields: no field named \"title-rating\""
+        );
+    }
+
+    #[test]
+    fn test_rename() {
+        let header = ["Title ", "year", "director"];
+        let names = |list: &str| -> Vec<Option<String>> {
+            resolve(list, &header).unwrap().into_iter().map(|spec| spec.name).collect()
+        };
+        assert_eq!(names("title:movie_title,2"), [Some("movie_title".to_string()), None]);
+        assert_eq!(names("/^d/:by,1.1-3:initials"), [Some("by".to_string()), Some("initials".to_string())]);
+        assert_eq!(names("/a:b|^y/:when"), [Some("when".to_string())]);
+        assert_eq!(names("3[:]1:first"), [Some("first".to_string())]);
+        assert_eq!(
+            resolve("1-2:x", &header).unwrap_err().to_string(),
+            "--fields: 1-2 selects 2 fields and cannot be renamed to \"x\""
+        );
+        assert!(FieldList::parse("title:").is_err());
+        assert!(FieldList::parse("!title:x").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let select = |sub: SubSelect, field: &str| {
+            String::from_utf8_lossy(sub.select(field.as_bytes())).into_owned()
+        };
+        assert_eq!(select(SubSelect::Chars(0..4), "2021-06-01"), "2021");
+        assert_eq!(select(SubSelect::Chars(1..3), "Émile"), "mi");
+        assert_eq!(select(SubSelect::Chars(0..1), "Émile"), "É");
+        assert_eq!(select(SubSelect::Chars(3..8), "Émile"), "le");
+        assert_eq!(select(SubSelect::Chars(6..8), "Émile"), "");
+        assert_eq!(select(SubSelect::Bytes(0..2), "Émile"), "É");
+        assert_eq!(select(SubSelect::Bytes(9..10), "Émile"), "");
+
+        let fields = |delim: &str, r| SubSelect::Fields(delim.to_string(), r);
+        assert_eq!(select(fields(":", 1..2), "a:bc:d"), "bc");
+        assert_eq!(select(fields(":", 0..1), "a:bc:d"), "a");
+        assert_eq!(select(fields(":", 1..3), "a:bc:d"), "bc:d");
+        assert_eq!(select(fields(":", 2..5), "a:bc:d"), "d");
+        assert_eq!(select(fields(":", 3..4), "a:bc:d"), "");
+        assert_eq!(select(fields(":", 0..1), "abc"), "abc");
+        assert_eq!(select(fields("→", 1..2), "a→b→c"), "b");
+    }
+}
@@ -0,0 +1,96 @@
+use crate::Dialect;
+use csv::ByteRecord;
+
+/// Number of records sampled by [`sniff`].
+pub const SNIFF_RECORDS: usize = 20;
+
+/// Maximum number of bytes read from the start of a file for [`sniff`].
+pub const SNIFF_BYTES: u64 = 64 << 10;
+
+const DELIMITERS: &[u8] = b"\t,;|:";
+const QUOTES: &[u8] = b"\"'";
+
+/// Guesses the delimiter and quote of the delimited text starting with
+/// `sample`, taking the other settings from `base`, or returns `None` if no
+/// candidate splits its records into several fields.
+///
+/// Each candidate delimiter and quote is scored on the first
+/// [`SNIFF_RECORDS`] records by how many of them have the most common field
+/// count, then by how many fields start with the quote, then by the number
+/// of fields.
+pub fn sniff(sample: &[u8], base: &Dialect) -> Option<Dialect> {
+    // The sample may end in the middle of a record.
+    let sample = match sample.iter().rposition(|&b| b == b'\n') {
+        Some(end) => &sample[..=end],
+        None => sample,
+    };
+    let mut best = None;
+    for &delimiter in DELIMITERS {
+        for &quote in QUOTES {
+            let dialect = Dialect { delimiter: char::from(delimiter), quote, ..base.clone() };
+            let (consistent, fields) = field_counts(sample, &dialect);
+            if fields < 2 {
+                continue;
+            }
+            let score = (consistent, quoted_fields(sample, delimiter, quote), fields);
+            if best.as_ref().is_none_or(|(best, _)| score > *best) {
+                best = Some((score, dialect));
+            }
+        }
+    }
+    best.map(|(_, dialect)| dialect)
+}
+
+/// Returns the most common number of fields in the sampled records and how
+/// many records have it.
+fn field_counts(sample: &[u8], dialect: &Dialect) -> (usize, usize) {
+    let mut reader = dialect.reader().from_reader(sample);
+    let mut record = ByteRecord::new();
+    let mut counts = vec![];
+    while counts.len() < SNIFF_RECORDS && reader.read_byte_record(&mut record).unwrap_or(false) {
+        counts.push(record.len());
+    }
+    counts.iter()
+        .map(|&n| (counts.iter().filter(|&&m| m == n).count(), n))
+        .max()
+        .unwrap_or_default()
+}
+
+/// Counts the fields of the sampled lines that start with `quote`.
+fn quoted_fields(sample: &[u8], delimiter: u8, quote: u8) -> usize {
+    sample.split(|&b| b == b'\n')
+        .take(SNIFF_RECORDS)
+        .map(|line| {
+            line.split(|&b| b == delimiter)
+                .filter(|field| field.first() == Some(&quote))
+                .count()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::Dialect;
+
+    fn sniff(sample: &[u8]) -> Option<Dialect> {
+        super::sniff(sample, &Dialect::default())
+    }
+
+    fn dialect(delimiter: u8, quote: u8) -> Option<Dialect> {
+        Some(Dialect { delimiter: char::from(delimiter), quote, ..Dialect::default() })
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b""), None);
+        assert_eq!(sniff(b"one\ntwo\n"), None);
+        assert_eq!(sniff(b"a,b,c\n1,2,3\n"), dialect(b',', b'"'));
+        assert_eq!(sniff(b"a\tb, c\n1\t2, 3\n"), dialect(b'\t', b'"'));
+        assert_eq!(sniff(b"a;b;c\n1,5;2;3\n4;5,5;6\n"), dialect(b';', b'"'));
+        assert_eq!(sniff(b"a|b\n1|2\n3|4"), dialect(b'|', b'"'));
+        assert_eq!(sniff(b"title,year\n\"Hello, World\",2019\n"), dialect(b',', b'"'));
+        assert_eq!(sniff(b"title;year\n'Hello; World';2019\n"), dialect(b';', b'\''));
+        // A partial last record is ignored.
+        assert_eq!(sniff(b"a,b\n1,2\n3,4\n5"), dialect(b',', b'"'));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{char_tokens, parse_char, select::find_name, MyResult};
+use csv::ByteRecord;
+
+/// An output template as given to `--format`, such as `{title} ({year})`.
+///
+/// A placeholder `{N}` stands for the `N`th output field and `{NAME}` for
+/// the output field whose header is `NAME`, matched as in the field list.
+/// Other text is written as is, with the escapes of `--delim` and with `{{`
+/// and `}}` standing for `{` and `}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Index(usize),
+    Name(String),
+}
+
+/// A piece of a record written through a [`Template`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Chunk<'a> {
+    /// Text of the template.
+    Text(&'a [u8]),
+    /// An output field.
+    Field(&'a [u8]),
+}
+
+impl Template {
+    pub fn parse(template: &str) -> MyResult<Self> {
+        let illegal = |why: &str| format!("illegal --format \"{}\": {}", template, why);
+        let mut pieces = vec![];
+        let mut text = String::new();
+        let mut tokens = char_tokens(template).into_iter().peekable();
+        while let Some(token) = tokens.next() {
+            match token {
+                "{" if tokens.next_if_eq(&"{").is_some() => text.push('{'),
+                "}" if tokens.next_if_eq(&"}").is_some() => text.push('}'),
+                "}" => return Err(From::from(illegal("unmatched }"))),
+                "{" => {
+                    let mut name = String::new();
+                    loop {
+                        match tokens.next() {
+                            Some("}") => break,
+                            Some(token) => name.push_str(token),
+                            None => return Err(From::from(illegal("unmatched {"))),
+                        }
+                    }
+                    if !text.is_empty() {
+                        pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    }
+                    pieces.push(match name.parse::<usize>() {
+                        Ok(0) => return Err(From::from(illegal("fields are numbered from 1"))),
+                        Ok(n) => Piece::Index(n - 1),
+                        Err(_) if name.is_empty() => return Err(From::from(illegal("empty {}"))),
+                        Err(_) => Piece::Name(name),
+                    });
+                }
+                _ if token.starts_with('\\') => text.push(parse_char("--format escape", token)?),
+                _ => text.push_str(token),
+            }
+        }
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        Ok(Template { pieces })
+    }
+
+    /// Returns the template with its names replaced by the positions of the
+    /// fields so named in `header`, the output header of a file.
+    pub fn resolve(&self, header: &ByteRecord) -> MyResult<Self> {
+        let pieces = self.pieces.iter()
+            .map(|piece| match piece {
+                Piece::Name(name) => match find_name("--format", name, header)? {
+                    Some(i) => Ok(Piece::Index(i)),
+                    None => Err(From::from(format!("--format: no field named \"{}\"", name))),
+                },
+                _ => Ok(piece.clone()),
+            })
+            .collect::<MyResult<_>>()?;
+        Ok(Template { pieces })
+    }
+
+    /// Returns the chunks of the record made of `fields`. Placeholders for
+    /// missing fields, or names that were not resolved, are left out.
+    pub fn chunks<'a>(&'a self, fields: &'a [&'a [u8]]) -> impl Iterator<Item = Chunk<'a>> {
+        self.pieces.iter().filter_map(move |piece| match piece {
+            Piece::Text(text) => Some(Chunk::Text(text.as_bytes())),
+            Piece::Index(i) => fields.get(*i).map(|f| Chunk::Field(f)),
+            Piece::Name(_) => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::{Chunk, Piece, Template};
+    use csv::ByteRecord;
+
+    fn render(template: &str, header: &[&str], fields: &[&str]) -> String {
+        let template = Template::parse(template).unwrap()
+            .resolve(&ByteRecord::from(header.to_vec()))
+            .unwrap();
+        let fields = fields.iter().map(|f| f.as_bytes()).collect::<Vec<_>>();
+        template.chunks(&fields)
+            .map(|chunk| match chunk {
+                Chunk::Text(text) => String::from_utf8_lossy(text).into_owned(),
+                Chunk::Field(field) => format!("<{}>", String::from_utf8_lossy(field)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Template::parse("{title} ({2})").unwrap().pieces,
+            [Piece::Name("title".to_string()), Piece::Text(" (".to_string()), Piece::Index(1), Piece::Text(")".to_string())]
+        );
+        assert_eq!(
+            Template::parse("{{\\t}}\\u{2192}").unwrap().pieces,
+            [Piece::Text("{\t}→".to_string())]
+        );
+        assert_eq!(
+            Template::parse("{title").unwrap_err().to_string(),
+            "illegal --format \"{title\": unmatched {"
+        );
+        assert!(Template::parse("title}").is_err());
+        assert!(Template::parse("{}").is_err());
+        assert!(Template::parse("{0}").is_err());
+        assert!(Template::parse("\\q").is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let header = ["Title ", "year"];
+        let fields = ["Jaws", "1975"];
+        assert_eq!(render("{title} ({year})", &header, &fields), "<Jaws> (<1975>)");
+        assert_eq!(render("{2}:{1}:{3}", &header, &fields), "<1975>:<Jaws>:");
+        assert_eq!(render("", &header, &fields), "");
+        let template = Template::parse("{rating}").unwrap();
+        assert_eq!(
+            template.resolve(&ByteRecord::from(header.to_vec())).unwrap_err().to_string(),
+            "--format: no field named \"rating\""
+        );
+    }
+}
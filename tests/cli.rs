@@ -1,3 +1,6 @@
+// Several tests pass their arguments to `Command::args` by reference.
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::Command;
 use predicates::prelude::*;
 use rand::{distributions::Alphanumeric, Rng};
@@ -9,6 +12,7 @@ const PRG: &str = "cutr";
 const CSV: &str = "tests/inputs/movies1.csv";
 const TSV: &str = "tests/inputs/movies1.tsv";
 const BOOKS: &str = "tests/inputs/books.tsv";
+const RAGGED: &str = "tests/inputs/movies2.tsv";
 
 // --------------------------------------------------
 fn random_string() -> String {
@@ -35,13 +39,54 @@ fn skips_bad_file() -> TestResult {
     let bad = gen_bad_file();
     let expected = format!("{}: .* [(]os error 2[)]", bad);
     Command::cargo_bin(PRG)?
-        .args(&["-f", "1", CSV, &bad, TSV])
+        .args(["-f", "1", CSV, &bad, TSV])
         .assert()
-        .success()
+        .code(1)
+        .stdout(predicate::str::contains("Les Misérables"))
         .stderr(predicate::str::is_match(expected)?);
     Ok(())
 }
 
+// --------------------------------------------------
+#[test]
+fn strict_stops_at_bad_file() -> TestResult {
+    let bad = gen_bad_file();
+    let expected = format!("{}: .* [(]os error 2[)]", bad);
+    Command::cargo_bin(PRG)?
+        .args(["--strict", "-f", "1", "-d", ",", CSV, &bad, TSV])
+        .assert()
+        .code(1)
+        .stdout("title\nThe Blues Brothers\nLes Misérables\n")
+        .stderr(predicate::str::is_match(expected)?);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn usage_error_exit_code() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([CSV, "-f", "0"])
+        .assert()
+        .code(2);
+    Command::cargo_bin(PRG)?
+        .args([CSV, "-f", "1", "--no-such-option"])
+        .assert()
+        .code(2);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn data_error_exit_code() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1", RAGGED, TSV])
+        .assert()
+        .code(3)
+        .stdout(predicate::str::contains("Les Misérables"))
+        .stderr(predicate::str::starts_with(RAGGED));
+    Ok(())
+}
+
 // --------------------------------------------------
 fn dies(args: &[&str], expected: &str) -> TestResult {
     Command::cargo_bin(PRG)?
@@ -61,7 +106,7 @@ fn dies_not_enough_args() -> TestResult {
 // --------------------------------------------------
 #[test]
 fn dies_bad_digit_field() -> TestResult {
-    let bad = random_string();
+    let bad = format!("1{}", random_string());
     dies(
         &[CSV, "-f", &bad],
         &format!("illegal list value: \"{}\"", &bad),
@@ -93,7 +138,7 @@ fn dies_bad_digit_chars() -> TestResult {
 fn dies_empty_delimiter() -> TestResult {
     dies(
         &[CSV, "-f", "1", "-d", ""],
-        "--delim \"\" must be a single byte",
+        "--delim \"\" must be a single character",
     )
 }
 
@@ -102,7 +147,7 @@ fn dies_empty_delimiter() -> TestResult {
 fn dies_bad_delimiter() -> TestResult {
     dies(
         &[CSV, "-f", "1", "-d", ",,"],
-        "--delim \",,\" must be a single byte",
+        "--delim \",,\" must be a single character",
     )
 }
 
@@ -337,3 +382,665 @@ fn tsv_c1_8() -> TestResult {
 fn repeated_value() -> TestResult {
     run(&[BOOKS, "-c", "1,1"], "tests/expected/books.c1,1.out")
 }
+
+// --------------------------------------------------
+#[test]
+fn quiet_on_broken_pipe() -> TestResult {
+    use std::io::{Read, Write};
+    use std::process::{Command, Stdio};
+    use std::thread;
+
+    let mut child = Command::new(assert_cmd::cargo::cargo_bin(PRG))
+        .args(["-f", "1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || {
+        let line = "The Blues Brothers\t1980\tJohn Landis\n".repeat(1000);
+        for _ in 0..1000 {
+            if stdin.write_all(line.as_bytes()).is_err() {
+                break;
+            }
+        }
+    });
+    let mut head = [0; 16];
+    child.stdout.take().unwrap().read_exact(&mut head)?;
+    let output = child.wait_with_output()?;
+    writer.join().unwrap();
+    assert_eq!(&head, b"The Blues Brothe");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stderr)?, "");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn threads_f1_3() -> TestResult {
+    run(
+        &[TSV, "--threads", "4", "-f", "1-3"],
+        "tests/expected/movies1.tsv.f1-3.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn threads_c1_8() -> TestResult {
+    run(
+        &[TSV, "--threads", "4", "-c", "1-8"],
+        "tests/expected/movies1.tsv.c1-8.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn threads_quoted_csv() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--threads", "2", "-d", ",", "-f", "1", "tests/inputs/movies2.csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with("To Sir, with Love\n"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_threads() -> TestResult {
+    dies(
+        &[CSV, "-f", "1", "--threads", "0"],
+        "--threads \"0\" must be a positive integer",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_f1_2_json() -> TestResult {
+    run(
+        &["tests/inputs/movies2.csv", "-f", "1-2", "-d", ",", "--output-format", "json"],
+        "tests/expected/movies2.csv.f1-2.dcomma.json.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_f1_2_ndjson() -> TestResult {
+    run(
+        &["tests/inputs/movies2.csv", "-f", "1-2", "-d", ",", "--output-format", "ndjson"],
+        "tests/expected/movies2.csv.f1-2.dcomma.ndjson.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn json_across_files() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-f", "2", "--output-format", "json", TSV, TSV])
+        .assert()
+        .success()
+        .stdout("[\n[\"year\"],\n[\"1980\"],\n[\"2019\"],\n[\"year\"],\n[\"1980\"],\n[\"2019\"]\n]\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn json_lines_keys_and_pointers() -> TestResult {
+    run(
+        &["tests/inputs/requests.ndjson", "--json-lines", "-f", "user,/request/status"],
+        "tests/expected/requests.ndjson.fuser,status.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn json_lines_malformed() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--json-lines", "-f", "user", "tests/inputs/malformed.ndjson"])
+        .assert()
+        .code(3)
+        .stdout("ken\n")
+        .stderr(predicate::str::starts_with(
+            "tests/inputs/malformed.ndjson: line 2: ",
+        ));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_f1_3_markdown() -> TestResult {
+    run(
+        &["tests/inputs/movies2.csv", "-f", "1-3", "-d", ",", "--header", "keep",
+            "--output-format", "markdown"],
+        "tests/expected/movies2.csv.f1-3.dcomma.markdown.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn books_f1_3_table() -> TestResult {
+    run(
+        &[BOOKS, "-f", "1,3", "--output-format", "table"],
+        "tests/expected/books.tsv.f1,3.table.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn header_once_across_files() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "1", "--header=once", CSV, CSV])
+        .assert()
+        .success()
+        .stdout("title\nThe Blues Brothers\nLes Misérables\nThe Blues Brothers\nLes Misérables\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn header_skip_chars() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-c", "1-3", "--header", "skip", TSV])
+        .assert()
+        .success()
+        .stdout("The\nLes\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn header_keyed_ndjson() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1,2", "--header", "keep", "--output-format", "ndjson", TSV])
+        .assert()
+        .success()
+        .stdout(
+            "{\"title\":\"The Blues Brothers\",\"year\":\"1980\"}\n\
+             {\"title\":\"Les Misérables\",\"year\":\"2019\"}\n",
+        );
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn filename_and_record_number() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-H", "-n", "-d", ",", "-f", "1", "tests/inputs/multiline.csv", CSV])
+        .assert()
+        .success()
+        .stdout(
+            "tests/inputs/multiline.csv:1:title\n\
+             tests/inputs/multiline.csv:2:A\n\
+             tests/inputs/multiline.csv:3:B\n\
+             tests/inputs/movies1.csv:1:title\n\
+             tests/inputs/movies1.csv:2:The Blues Brothers\n\
+             tests/inputs/movies1.csv:3:Les Misérables\n",
+        );
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn record_number_prefix_sep() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-n", "--prefix-sep", " | ", "-c", "1-3", "--header", "skip", TSV])
+        .assert()
+        .success()
+        .stdout("2 | The\n3 | Les\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn where_numeric_by_name() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "1", "--header", "keep", "--where", "year<2000", "tests/inputs/movies2.csv"])
+        .assert()
+        .success()
+        .stdout("title\nThe Blues Brothers\nTo Sir, with Love\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn where_regex_and_literal() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1,2", "--where", "1~^(Émile|Jules)", "--where", "2!=1870", BOOKS])
+        .assert()
+        .success()
+        .stdout("Émile Zola\t1865\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_where() -> TestResult {
+    dies(
+        &[CSV, "-f", "1", "--where", "year"],
+        "illegal --where expression: \"year\"",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn rows_skip_limit() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-c", "1-3", "--header", "keep", "--rows", "1,3-10", BOOKS])
+        .assert()
+        .success()
+        .stdout("Aut\nÉmi\nJul\n");
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1", "--skip", "1", "--limit", "1", BOOKS, TSV])
+        .assert()
+        .success()
+        .stdout("Émile Zola\nThe Blues Brothers\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn limit_stops_reading() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1", "--limit", "1", RAGGED])
+        .assert()
+        .success()
+        .stdout("title\n");
+    Command::cargo_bin(PRG)?
+        .args(["--json-lines", "-f", "user", "--rows", "1", "tests/inputs/malformed.ndjson"])
+        .assert()
+        .success()
+        .stdout("ken\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_limit() -> TestResult {
+    dies(
+        &[CSV, "-f", "1", "--limit", "ten"],
+        "--limit \"ten\" must be a non-negative integer",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn regex_groups() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--regex", r"(\d+)ms .* user=(?P<user>\w+)", "-f", "user,1", "-d", ",", "tests/inputs/access.log"])
+        .assert()
+        .success()
+        .stdout("ken,12\nemile,340\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn regex_unmatched() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--regex", r"(\d+)ms", "--unmatched", "warn", "tests/inputs/access.log"])
+        .assert()
+        .success()
+        .stdout("12\n340\n")
+        .stderr("tests/inputs/access.log: line 2: no match for --regex\n");
+    Command::cargo_bin(PRG)?
+        .args(["--regex", r"(\d+)ms", "--unmatched", "error", "tests/inputs/access.log"])
+        .assert()
+        .code(3)
+        .stdout("12\n")
+        .stderr("tests/inputs/access.log: line 2: no match for --regex\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn sub_selection() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([BOOKS, "-f", "1[ ]2,2.3-4,3.b1-3", "--rows", "2-3"])
+        .assert()
+        .success()
+        .stdout("Zola\t65\tLa \nBeckett\t52\tWai\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_sub_selection() -> TestResult {
+    dies(&[BOOKS, "-f", "1[ 2"], "illegal list value: \"1[ 2\"")
+}
+
+// --------------------------------------------------
+#[test]
+fn delim_auto() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--delim", "auto", "-v", "-f", "1,3", "--rows", "2", CSV, BOOKS])
+        .assert()
+        .success()
+        .stdout("The Blues Brothers,John Landis\nÉmile Zola\tLa Confession de Claude\n")
+        .stderr(format!(
+            "{}: --delim auto: delimiter ',', quote '\"'\n{}: --delim auto: delimiter '\\t', quote '\"'\n",
+            CSV, BOOKS
+        ));
+    Command::cargo_bin(PRG)?
+        .args(["--delim", "auto", "-v", "-f", "2"])
+        .write_stdin("title;year\n'Hello; World';2019\n")
+        .assert()
+        .success()
+        .stdout("year\n2019\n")
+        .stderr("-: --delim auto: delimiter ';', quote '\\''\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn fill_missing_fields() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1,3", "--fill", "NA", "--rows", "1-2", RAGGED])
+        .assert()
+        .success()
+        .stdout("title\tNA\nThe Blues Brothers\tJohn Landis\n");
+    Command::cargo_bin(PRG)?
+        .args(["-c", "2-5", "--fill", "."])
+        .write_stdin("ab\nabcdé\n")
+        .assert()
+        .success()
+        .stdout("b...\nbcdé\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn strict_fields() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1,3", "--strict-fields", TSV, RAGGED])
+        .assert()
+        .code(3)
+        .stderr(format!("{}: line 1: missing field 3\n", RAGGED));
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1,3", "--strict-fields"])
+        .write_stdin("a\tb\tc\nd\n")
+        .assert()
+        .code(3)
+        .stdout("a\tc\n")
+        .stderr("-: line 2: missing field 3\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn ragged_rows() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1", "--ragged", "allow", "--rows", "2", RAGGED])
+        .assert()
+        .success()
+        .stdout("The Blues Brothers\n")
+        .stderr("");
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1", "--ragged", "warn", "--skip", "2", RAGGED])
+        .assert()
+        .success()
+        .stdout("Les Misérables\nTo Sir, with Love\n")
+        .stderr(format!(
+            "{0}: line 2: found 3 fields, expected 2\n{0}: line 3: found 3 fields, expected 2\n\
+             {0}: line 4: found 3 fields, expected 2\n",
+            RAGGED
+        ));
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1", "--ragged", "error", RAGGED, TSV])
+        .assert()
+        .code(3)
+        .stdout("title\ntitle\nThe Blues Brothers\nLes Misérables\n")
+        .stderr(format!("{}: line 2: found 3 fields, expected 2\n", RAGGED));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_dialect() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([
+            "-d", ",", "--quote", "'", "--escape", "\\", "--no-double-quote", "--comment", "#",
+            "--trim", "-f", "2,1", "--output-quoting", "necessary", "tests/inputs/vendor.csv",
+        ])
+        .assert()
+        .success()
+        .stdout("name,id\n'O\\'Brien, Pat',1\n");
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "--comment", "#", "--ragged", "allow", "-f", "2", "--output-quoting", "always", "tests/inputs/vendor.csv"])
+        .assert()
+        .success()
+        .stdout("\"name\"\n\"'O\\'Brien\"\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_quote() -> TestResult {
+    dies(&[CSV, "-f", "1", "--quote", "''"], "--quote \"''\" must be a single byte")
+}
+
+// --------------------------------------------------
+#[test]
+fn raw_escape() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--raw-escape", "\\", "-f", "2", "tests/inputs/copy.tsv"])
+        .assert()
+        .success()
+        .stdout("note\nsay \"hi\\\tthere\"\nC:\\\\temp\\tx\n");
+    Command::cargo_bin(PRG)?
+        .args(["--raw-escape", "\\", "--unescape", "-f", "2", "tests/inputs/copy.tsv"])
+        .assert()
+        .success()
+        .stdout("note\nsay \"hi\tthere\"\nC:\\temp\tx\n");
+    Command::cargo_bin(PRG)?
+        .args(["--raw-escape", "\\", "--unescape", "-f", "1-4", "--output-delim", ","])
+        .write_stdin("1\t\\N\tx\\011y\tb\\bz\n")
+        .assert()
+        .success()
+        .stdout("1,,x\ty,b\x08z\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn delim_escapes() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-d", "\\t", "-f", "1", "--rows", "2", BOOKS])
+        .assert()
+        .success()
+        .stdout("Émile Zola\n");
+    Command::cargo_bin(PRG)?
+        .args(["-d", "\\u{2192}", "-f", "3,1"])
+        .write_stdin("a→\"b→c\"\n")
+        .assert()
+        .success()
+        .stdout("c\"→a\n");
+    Command::cargo_bin(PRG)?
+        .args(["-d", "¦", "-f", "2"])
+        .write_stdin("a¦b\n")
+        .assert()
+        .success()
+        .stdout("b\n");
+    dies(
+        &["-d", "→", "--quote", "'", "-f", "1", CSV],
+        "--quote, --escape and --no-double-quote need an ASCII --delim",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn delim_set() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--delim-set", ",;|", "-f", "1,4"])
+        .write_stdin("a,b;c|d\n1;2,3|4\n")
+        .assert()
+        .success()
+        .stdout("a,d\n1,4\n");
+    Command::cargo_bin(PRG)?
+        .args(["--delim-set", "\\t|", "-f", "3,1", "--output-delim", " \\u{2192} "])
+        .write_stdin("a\tb|c\n")
+        .assert()
+        .success()
+        .stdout("c → a\n");
+    Command::cargo_bin(PRG)?
+        .args(["-f", "1,3", "--output-delim", ",", "--rows", "2", BOOKS])
+        .assert()
+        .success()
+        .stdout("Émile Zola,La Confession de Claude\n");
+    dies(
+        &["-d", "auto", "--delim-set", ";", "-f", "1", CSV],
+        "--delim cannot be used with --delim-set",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn header_selectors() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "!year", "--rows", "1-2", CSV])
+        .assert()
+        .success()
+        .stdout("title,director\nThe Blues Brothers,John Landis\n");
+    Command::cargo_bin(PRG)?
+        .args(["-f", "/^(Year|Title)$/.1-4", "--threads", "2", BOOKS])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Year\tTitl\n1865\tLa C\n"));
+    Command::cargo_bin(PRG)?
+        .args(["-f", "/^metric_/", BOOKS])
+        .assert()
+        .code(3)
+        .stderr(format!("{}: --fields: no field matches /^metric_/\n", BOOKS));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn header_names() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "DIRECTOR, title-year", "--rows", "1-2", CSV])
+        .assert()
+        .success()
+        .stdout("director,title,year\nJohn Landis,The Blues Brothers,1980\n");
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "title.1-4,director[ ]2", "--rows", "2", CSV])
+        .assert()
+        .success()
+        .stdout("The ,Landis\n");
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "year-title", CSV])
+        .assert()
+        .code(3)
+        .stderr(format!("{}: --fields: \"year\" comes after \"title\" in the header\n", CSV));
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "rating", CSV])
+        .assert()
+        .code(3)
+        .stderr(format!("{}: --fields: no field named \"rating\"\n", CSV));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn rename_fields() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "director:by,title:movie_title", "--header", "keep", "--rows", "1", CSV])
+        .assert()
+        .success()
+        .stdout("by,movie_title\nJohn Landis,The Blues Brothers\n");
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "title:movie_title,2.1-2:decade", "--header", "skip", "--output-format", "ndjson",
+            "--rows", "1", CSV])
+        .assert()
+        .success()
+        .stdout("{\"movie_title\":\"The Blues Brothers\",\"decade\":\"19\"}\n");
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "/e/:x", CSV])
+        .assert()
+        .code(3)
+        .stderr(format!("{}: --fields: /e/ selects 3 fields and cannot be renamed to \"x\"\n", CSV));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn add_column() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "title", "--header", "keep", "--rows", "1-2", "--add-column", "source=fileA",
+            "--add-column", "file=$FILENAME", "--add-column", "line=$LINE", "--add-column", "nf=$NF", CSV])
+        .assert()
+        .success()
+        .stdout(format!(
+            "title,source,file,line,nf\nThe Blues Brothers,fileA,{0},2,3\nLes Misérables,fileA,{0},3,3\n",
+            CSV
+        ));
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "1", "--header", "skip", "--output-format", "ndjson", "--rows", "1",
+            "--add-column", "price=$$5", CSV])
+        .assert()
+        .success()
+        .stdout("{\"title\":\"The Blues Brothers\",\"price\":\"$5\"}\n");
+    dies(
+        &["-f", "1", "--add-column", "n=$NR", CSV],
+        "illegal --add-column \"n=$NR\": unknown variable $NR",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn format_template() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "--format", "{title} ({YEAR})", "--header", "skip", "--rows", "1-2", CSV])
+        .assert()
+        .success()
+        .stdout("The Blues Brothers (1980)\nLes Misérables (2012)\n");
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "director:by,title", "--add-column", "line=$LINE", "--format",
+            "{{{line}}}\\t{2} by {by}", "--rows", "2", CSV])
+        .assert()
+        .success()
+        .stdout("{2}\tThe Blues Brothers by John Landis\n");
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "--format", "{rating}", CSV])
+        .assert()
+        .code(3)
+        .stderr(format!("{}: --format: no field named \"rating\"\n", CSV));
+    dies(&["--format", "{1", CSV], "illegal --format \"{1\": unmatched {")?;
+    dies(
+        &["--format", "{1}", "--output-format", "json", CSV],
+        "--format requires --output-format text",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn prefix_table_header() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-H", "-n", "-d", ",", "-f", "1-2", "--header", "keep", "--rows", "1",
+            "--output-format", "markdown", CSV])
+        .assert()
+        .success()
+        .stdout(format!(
+            "| filename{0} | record | title              | year |\n\
+             | --------{1} | ------ | ------------------ | ---- |\n\
+             | {2} | 2      | The Blues Brothers | 1980 |\n",
+            " ".repeat(CSV.len() - 8),
+            "-".repeat(CSV.len() - 8),
+            CSV
+        ));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn markdown_without_header() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-d", ",", "-f", "title,year", "--header", "skip", "--rows", "1",
+            "--output-format", "markdown", CSV])
+        .assert()
+        .success()
+        .stdout(
+            "| 1                  | 2    |\n\
+             | ------------------ | ---- |\n\
+             | The Blues Brothers | 1980 |\n",
+        );
+    Ok(())
+}